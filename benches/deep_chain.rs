@@ -0,0 +1,36 @@
+//! Times staging alternating chains of `matadd` and `matmul`, which take time
+//! linear in their depth: `cargo bench --bench deep_chain`.
+
+use std::time::Instant;
+
+use metastage::{
+    matadd::matadd,
+    matmul::matmul,
+    sam::{LevelFormat, SamOps},
+    sym::{Expr, ScopeRef},
+    tensor::{InputTensor, Tensor},
+};
+
+fn chain(depth: usize) -> Tensor {
+    let leaf = |level: usize| {
+        InputTensor {
+            name: format!("A{level}"),
+            formats: vec![LevelFormat::Compressed; 2],
+        }
+        .stage()
+    };
+    (1..depth).fold(leaf(0), |chain, level| match level % 2 {
+        0 => matmul(chain, leaf(level)),
+        _ => matadd(chain, leaf(level)),
+    })
+}
+
+fn main() {
+    for depth in [5, 10, 20, 40, 80] {
+        let start = Instant::now();
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        chain(depth).stage_output("X", &[LevelFormat::Compressed; 2], root, &scope);
+        println!("depth {depth:>3}: staged in {:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{
+    lower::{lower, IndexExpr, LowerError},
+    tensor::Tensor,
+};
+
+/// Multiplies `a` and `b`, whose levels are labelled by `a_indices` and
+/// `b_indices` in storage order, and sums over every label missing from
+/// `output`. Labels shared by both operands are intersected.
+///
+/// SpMV is `contract(a, &['i', 'j'], x, &['j'], &['i'])`, batched matmul
+/// `contract(a, &['b', 'i', 'k'], b, &['b', 'j', 'k'], &['b', 'i', 'j'])`.
+pub fn contract(
+    a: Tensor,
+    a_indices: &[char],
+    b: Tensor,
+    b_indices: &[char],
+    output: &[char],
+) -> Result<Tensor, LowerError> {
+    let expr = IndexExpr::Mul(
+        Box::new(IndexExpr::Access {
+            operand: 0,
+            indices: a_indices.to_vec(),
+        }),
+        Box::new(IndexExpr::Access {
+            operand: 1,
+            indices: b_indices.to_vec(),
+        }),
+    );
+    lower(expr, vec![a, b], output)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use fxhash::FxHashMap;
+
+    use crate::{
+        lower::LowerError,
+        sam::LevelFormat::Compressed,
+        storage::SparseTensor,
+        sym::ScopeRef,
+        tensor::InputTensor,
+        test_util::{named, stage_and_execute},
+    };
+
+    use super::contract;
+
+    /// A tensor with roughly a third of its entries set, from a fixed seed.
+    fn sparse(shape: Vec<usize>, seed: u64) -> SparseTensor {
+        let mut state = seed;
+        let mut entries = vec![];
+        let size: usize = shape.iter().product();
+        for mut position in 0..size {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            if (state >> 33).is_multiple_of(3) {
+                let mut coords = vec![0; shape.len()];
+                for (coord, dim) in coords.iter_mut().zip(&shape).rev() {
+                    *coord = position % dim;
+                    position /= dim;
+                }
+                entries.push((coords, ((state >> 40) % 9 + 1) as f64));
+            }
+        }
+        SparseTensor::from_coo(shape, entries)
+    }
+
+    /// Contracts two tensors entry by entry.
+    fn reference(
+        a: &SparseTensor,
+        a_indices: &[char],
+        b: &SparseTensor,
+        b_indices: &[char],
+        output: &[char],
+    ) -> Vec<(Vec<usize>, f64)> {
+        let mut result = BTreeMap::new();
+        for (a_coords, a_val) in a.to_coo() {
+            for (b_coords, b_val) in b.to_coo() {
+                let mut bound = FxHashMap::default();
+                let consistent = a_indices
+                    .iter()
+                    .zip(&a_coords)
+                    .chain(b_indices.iter().zip(&b_coords))
+                    .all(|(index, coord)| *bound.entry(*index).or_insert(*coord) == *coord);
+                if consistent {
+                    let coords: Vec<usize> = output.iter().map(|index| bound[index]).collect();
+                    *result.entry(coords).or_insert(0.0) += a_val * b_val;
+                }
+            }
+        }
+        result.into_iter().collect()
+    }
+
+    fn check(
+        a_shape: Vec<usize>,
+        a_indices: &str,
+        b_shape: Vec<usize>,
+        b_indices: &str,
+        output: &str,
+    ) {
+        let (a_indices, b_indices, output): (Vec<char>, Vec<char>, Vec<char>) = (
+            a_indices.chars().collect(),
+            b_indices.chars().collect(),
+            output.chars().collect(),
+        );
+        let input = |name: &str, order| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; order],
+            }
+            .stage()
+        };
+        let shape = output
+            .iter()
+            .map(|index| match a_indices.iter().position(|i| i == index) {
+                Some(level) => a_shape[level],
+                None => b_shape[b_indices.iter().position(|i| i == index).unwrap()],
+            })
+            .collect();
+        let (a, b) = (sparse(a_shape, 1), sparse(b_shape, 2));
+        let tensor = contract(
+            input("A", a_indices.len()),
+            &a_indices,
+            input("B", b_indices.len()),
+            &b_indices,
+            &output,
+        )
+        .unwrap();
+
+        let inputs = named([("A", a.clone()), ("B", b.clone())]);
+        let formats = vec![Compressed; output.len()];
+        let found = stage_and_execute(tensor, &formats, shape, &inputs, &ScopeRef::default());
+        assert_eq!(
+            found.to_coo(),
+            reference(&a, &a_indices, &b, &b_indices, &output)
+        );
+    }
+
+    #[test]
+    fn test_spmv() {
+        check(vec![4, 5], "ij", vec![5], "j", "i");
+    }
+
+    #[test]
+    fn test_ttv() {
+        check(vec![3, 4, 5], "ijk", vec![5], "k", "ij");
+    }
+
+    #[test]
+    fn test_ttm() {
+        check(vec![3, 4, 5], "ijk", vec![2, 5], "lk", "ijl");
+    }
+
+    #[test]
+    fn test_batched_matmul() {
+        check(vec![2, 3, 4], "bik", vec![2, 3, 4], "bjk", "bij");
+    }
+
+    #[test]
+    fn test_ttt() {
+        check(vec![3, 4, 5], "ijk", vec![2, 4, 5], "ljk", "il");
+    }
+
+    #[test]
+    fn test_row_merged() {
+        check(vec![4, 5], "ik", vec![5, 3], "kj", "ij");
+        check(vec![3, 4, 5], "kij", vec![3, 2], "kl", "ijl");
+    }
+
+    #[test]
+    fn test_outer_product() {
+        check(vec![4], "i", vec![3], "j", "ij");
+    }
+
+    #[test]
+    fn test_unsupported_order() {
+        let input = |name: &str| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; 2],
+            }
+            .stage()
+        };
+        // Reading both operands by rows visits i before j.
+        let result = contract(
+            input("A"),
+            &['i', 'k'],
+            input("B"),
+            &['k', 'j'],
+            &['j', 'i'],
+        );
+        assert!(matches!(result.err(), Some(LowerError::LoopOrder(_))));
+    }
+}
@@ -0,0 +1,757 @@
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{
+    matmul::Dataflow,
+    sam::{LevelFormat, PrimitiveOp, SamOps},
+    sym::{Expr, ScopeRef},
+    tensor::InputTensor,
+    tensor_expr::TensorExpr,
+};
+
+/// An equivalence class of an `EGraph`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Id(usize);
+
+/// A `TensorExpr` node whose operands are equivalence classes rather than
+/// expressions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ENode {
+    Input(InputTensor),
+    Elementwise {
+        op: PrimitiveOp,
+        lhs: Id,
+        rhs: Id,
+    },
+    Matmul {
+        dataflow: Dataflow,
+        lhs: Id,
+        rhs: Id,
+    },
+    Contract {
+        lhs: Id,
+        lhs_indices: Vec<char>,
+        rhs: Id,
+        rhs_indices: Vec<char>,
+        output: Vec<char>,
+    },
+}
+
+impl ENode {
+    /// The operands, left to right.
+    pub fn children(&self) -> Vec<Id> {
+        match self {
+            ENode::Input(_) => vec![],
+            ENode::Elementwise { lhs, rhs, .. }
+            | ENode::Matmul { lhs, rhs, .. }
+            | ENode::Contract { lhs, rhs, .. } => vec![*lhs, *rhs],
+        }
+    }
+
+    /// This node over other operands. Inputs have none to replace.
+    pub fn with_children(&self, lhs: Id, rhs: Id) -> ENode {
+        let mut node = self.clone();
+        match &mut node {
+            ENode::Input(_) => {}
+            ENode::Elementwise { lhs: l, rhs: r, .. }
+            | ENode::Matmul { lhs: l, rhs: r, .. }
+            | ENode::Contract { lhs: l, rhs: r, .. } => (*l, *r) = (lhs, rhs),
+        }
+        node
+    }
+
+    /// Whether this node multiplies its operands, and so distributes over
+    /// their sums.
+    fn is_product(&self) -> bool {
+        matches!(
+            self,
+            ENode::Elementwise {
+                op: PrimitiveOp::Mul,
+                ..
+            } | ENode::Matmul { .. }
+                | ENode::Contract { .. }
+        )
+    }
+
+    /// Whether the classes `operands` may stand in for operand `port` of
+    /// this node. A contraction indexes each operand, so they need as many
+    /// levels as it has indices for it.
+    fn fits(&self, egraph: &EGraph, port: usize, operands: [Id; 2]) -> bool {
+        match self {
+            ENode::Contract {
+                lhs_indices,
+                rhs_indices,
+                ..
+            } => {
+                let indices = [lhs_indices, rhs_indices][port];
+                operands
+                    .iter()
+                    .all(|&id| egraph.rank(id) == Some(indices.len()))
+            }
+            _ => true,
+        }
+    }
+
+    fn to_expr(&self, child: impl Fn(Id) -> TensorExpr) -> TensorExpr {
+        let child = |id| Box::new(child(id));
+        match self {
+            ENode::Input(input) => TensorExpr::Input(input.clone()),
+            ENode::Elementwise { op, lhs, rhs } => TensorExpr::Elementwise {
+                op: *op,
+                lhs: child(*lhs),
+                rhs: child(*rhs),
+            },
+            ENode::Matmul { dataflow, lhs, rhs } => TensorExpr::Matmul {
+                dataflow: *dataflow,
+                lhs: child(*lhs),
+                rhs: child(*rhs),
+            },
+            ENode::Contract {
+                lhs,
+                lhs_indices,
+                rhs,
+                rhs_indices,
+                output,
+            } => TensorExpr::Contract {
+                lhs: child(*lhs),
+                lhs_indices: lhs_indices.clone(),
+                rhs: child(*rhs),
+                rhs_indices: rhs_indices.clone(),
+                output: output.clone(),
+            },
+        }
+    }
+}
+
+/// Finds the classes equivalent to the one holding a node, adding the nodes
+/// they need to the graph.
+pub type RuleFn = fn(&mut EGraph, &ENode) -> Vec<Id>;
+
+pub struct Rule {
+    pub name: &'static str,
+    pub apply: RuleFn,
+}
+
+/// How many times `saturate` applies its rules, and how many nodes it lets
+/// the graph grow to, before giving up on saturating it.
+const MAX_ITERATIONS: usize = 32;
+const MAX_NODES: usize = 10_000;
+
+/// Equivalence classes of tensor expressions, which rules grow until every
+/// way they know of writing an expression is in its class.
+#[derive(Debug, Default)]
+pub struct EGraph {
+    parents: Vec<Id>,
+    classes: FxHashMap<Id, Vec<ENode>>,
+    memo: FxHashMap<ENode, Id>,
+}
+
+impl EGraph {
+    /// The class `id` has been merged into.
+    pub fn find(&self, mut id: Id) -> Id {
+        while self.parents[id.0] != id {
+            id = self.parents[id.0];
+        }
+        id
+    }
+
+    /// The nodes of the class `id` has been merged into.
+    pub fn nodes(&self, id: Id) -> &[ENode] {
+        &self.classes[&self.find(id)]
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.classes.values().map(Vec::len).sum()
+    }
+
+    pub fn class_count(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// The number of levels of the tensors in the class of `id`, as
+    /// `TensorExpr::rank`, or `None` if no node of it has a known rank.
+    pub fn rank(&self, id: Id) -> Option<usize> {
+        self.rank_along(id, &mut vec![])
+    }
+
+    /// `rank`, skipping the classes on `path` to it, whose rank is being
+    /// looked for already.
+    fn rank_along(&self, id: Id, path: &mut Vec<Id>) -> Option<usize> {
+        let id = self.find(id);
+        if path.contains(&id) {
+            return None;
+        }
+        path.push(id);
+        let rank = self.classes[&id].iter().find_map(|node| match node {
+            ENode::Input(input) => Some(input.dims()),
+            ENode::Matmul { .. } => Some(2),
+            ENode::Contract { output, .. } => Some(output.len()),
+            ENode::Elementwise { lhs, .. } => self.rank_along(*lhs, path),
+        });
+        path.pop();
+        rank
+    }
+
+    fn canonicalize(&self, node: &ENode) -> ENode {
+        match node.children()[..] {
+            [lhs, rhs] => node.with_children(self.find(lhs), self.find(rhs)),
+            _ => node.clone(),
+        }
+    }
+
+    /// The class of `node`, which is added to a class of its own if it is
+    /// not in the graph yet.
+    pub fn add(&mut self, node: ENode) -> Id {
+        let node = self.canonicalize(&node);
+        if let Some(&id) = self.memo.get(&node) {
+            return self.find(id);
+        }
+        let id = Id(self.parents.len());
+        self.parents.push(id);
+        self.classes.insert(id, vec![node.clone()]);
+        self.memo.insert(node, id);
+        id
+    }
+
+    /// The class of `expr`, adding each of its subexpressions in turn.
+    pub fn add_expr(&mut self, expr: &TensorExpr) -> Id {
+        let children: Vec<_> = expr
+            .children()
+            .into_iter()
+            .map(|child| self.add_expr(child))
+            .collect();
+        let node = match expr {
+            TensorExpr::Input(input) => ENode::Input(input.clone()),
+            TensorExpr::Elementwise { op, .. } => ENode::Elementwise {
+                op: *op,
+                lhs: children[0],
+                rhs: children[1],
+            },
+            TensorExpr::Matmul { dataflow, .. } => ENode::Matmul {
+                dataflow: *dataflow,
+                lhs: children[0],
+                rhs: children[1],
+            },
+            TensorExpr::Contract {
+                lhs_indices,
+                rhs_indices,
+                output,
+                ..
+            } => ENode::Contract {
+                lhs: children[0],
+                lhs_indices: lhs_indices.clone(),
+                rhs: children[1],
+                rhs_indices: rhs_indices.clone(),
+                output: output.clone(),
+            },
+        };
+        self.add(node)
+    }
+
+    /// Merges the classes of `a` and `b`, returning whether they were apart.
+    /// Call `rebuild` before looking nodes up again.
+    pub fn union(&mut self, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (root, merged) = (a.min(b), a.max(b));
+        self.parents[merged.0] = root;
+        let nodes = self.classes.remove(&merged).unwrap();
+        self.classes.get_mut(&root).unwrap().extend(nodes);
+        true
+    }
+
+    /// Restores congruence after `union`: nodes whose operands have been
+    /// merged are merged too, until no two classes hold the same node.
+    pub fn rebuild(&mut self) {
+        loop {
+            let mut memo: FxHashMap<ENode, Id> = FxHashMap::default();
+            let mut congruent = vec![];
+            let ids: Vec<_> = self.classes.keys().copied().collect();
+            for id in ids {
+                let mut nodes: Vec<_> = self.classes[&id]
+                    .iter()
+                    .map(|node| self.canonicalize(node))
+                    .collect();
+                let mut seen = FxHashSet::default();
+                nodes.retain(|node| seen.insert(node.clone()));
+                for node in &nodes {
+                    if let Some(&other) = memo.get(node) {
+                        congruent.push((id, other));
+                    } else {
+                        memo.insert(node.clone(), id);
+                    }
+                }
+                self.classes.insert(id, nodes);
+            }
+            self.memo = memo;
+            let mut merged = false;
+            for (a, b) in congruent {
+                merged |= self.union(a, b);
+            }
+            if !merged {
+                return;
+            }
+        }
+    }
+
+    /// Applies `rules` to every node until they find nothing new, returning
+    /// whether they did before the graph grew too large. The graph is left
+    /// rebuilt either way.
+    pub fn saturate(&mut self, rules: &[Rule]) -> bool {
+        for _ in 0..MAX_ITERATIONS {
+            let matches: Vec<_> = self
+                .classes
+                .iter()
+                .flat_map(|(&id, nodes)| nodes.iter().map(move |node| (id, node.clone())))
+                .collect();
+            let mut changed = false;
+            for (id, node) in matches {
+                for rule in rules {
+                    for equivalent in (rule.apply)(self, &node) {
+                        changed |= self.union(id, equivalent);
+                    }
+                    if self.node_count() > MAX_NODES {
+                        self.rebuild();
+                        return false;
+                    }
+                }
+            }
+            self.rebuild();
+            if !changed {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The cheapest expression in the class of `root`, and its cost. `cost`
+    /// prices a node given the cost of each of its operands, and must price
+    /// it above them.
+    pub fn extract(
+        &self,
+        root: Id,
+        cost: &impl Fn(&ENode, &[usize]) -> usize,
+    ) -> (usize, TensorExpr) {
+        let mut best: FxHashMap<Id, (usize, &ENode)> = FxHashMap::default();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (&id, nodes) in &self.classes {
+                for node in nodes {
+                    let children: Option<Vec<_>> = node
+                        .children()
+                        .into_iter()
+                        .map(|child| best.get(&self.find(child)).map(|(cost, _)| *cost))
+                        .collect();
+                    let Some(children) = children else {
+                        continue;
+                    };
+                    let cost = cost(node, &children);
+                    if best.get(&id).is_none_or(|(best, _)| cost < *best) {
+                        best.insert(id, (cost, node));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        let build = |id| self.build(id, &best);
+        (best[&self.find(root)].0, build(root))
+    }
+
+    fn build(&self, id: Id, best: &FxHashMap<Id, (usize, &ENode)>) -> TensorExpr {
+        best[&self.find(id)]
+            .1
+            .to_expr(|child| self.build(child, best))
+    }
+}
+
+/// Counts the nodes of an expression.
+pub fn ast_size(_: &ENode, children: &[usize]) -> usize {
+    1 + children.iter().sum::<usize>()
+}
+
+/// Prices the nodes of `egraph` by the `SamOps` that staging them over stored
+/// operands of the same ranks takes, writers included, plus the price of
+/// those operands. Nodes that do not lower cost `usize::MAX`.
+pub fn staged_ops(egraph: &EGraph) -> impl Fn(&ENode, &[usize]) -> usize + '_ {
+    move |node, children| {
+        let expr = node.to_expr(|id| {
+            TensorExpr::Input(InputTensor {
+                name: format!("T{}", egraph.find(id).0),
+                formats: vec![LevelFormat::Compressed; egraph.rank(id).unwrap_or(0)],
+            })
+        });
+        let scope = ScopeRef::default();
+        let staged = expr.lower().ok().and_then(|tensor| {
+            let root = SamOps::Root.try_stage(&scope).ok()?[0];
+            let formats = vec![LevelFormat::Compressed; tensor.meta.len()];
+            tensor.try_stage_output("X", &formats, root, &scope).ok()
+        });
+        let own = match staged {
+            Some(_) => scope.borrow().program_order().count(),
+            None => usize::MAX,
+        };
+        children
+            .iter()
+            .fold(own, |cost, child| cost.saturating_add(*child))
+    }
+}
+
+fn sums(egraph: &EGraph, id: Id) -> Vec<(Id, Id)> {
+    egraph
+        .nodes(id)
+        .iter()
+        .filter_map(|node| match *node {
+            ENode::Elementwise {
+                op: PrimitiveOp::Add,
+                lhs,
+                rhs,
+            } => Some((lhs, rhs)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn add(egraph: &mut EGraph, lhs: Id, rhs: Id) -> Id {
+    egraph.add(ENode::Elementwise {
+        op: PrimitiveOp::Add,
+        lhs,
+        rhs,
+    })
+}
+
+/// The algebra of tensor expressions:
+///
+/// - commute: elementwise sums and products are commutative.
+/// - associate: elementwise sums are associative, both ways.
+/// - distribute: products (elementwise, matmul and contractions) distribute
+///   over sums of either operand.
+/// - factor: sums of products sharing an operand factor it out, undoing
+///   distribute.
+///
+/// Contractions only distribute over, or factor out, sums whose terms have
+/// as many levels as the contraction has indices for that operand.
+///
+/// Matmul dataflows are left alone, as each expects its operands stored
+/// differently.
+pub fn rules() -> Vec<Rule> {
+    vec![
+        Rule {
+            name: "commute",
+            apply: |egraph, node| match *node {
+                ENode::Elementwise { op, lhs, rhs } => {
+                    vec![egraph.add(ENode::Elementwise {
+                        op,
+                        lhs: rhs,
+                        rhs: lhs,
+                    })]
+                }
+                _ => vec![],
+            },
+        },
+        Rule {
+            name: "associate",
+            apply: |egraph, node| {
+                let ENode::Elementwise {
+                    op: PrimitiveOp::Add,
+                    lhs,
+                    rhs,
+                } = *node
+                else {
+                    return vec![];
+                };
+                let mut equivalent = vec![];
+                // (a + b) + c => a + (b + c)
+                for (a, b) in sums(egraph, lhs) {
+                    let bc = add(egraph, b, rhs);
+                    equivalent.push(add(egraph, a, bc));
+                }
+                // a + (b + c) => (a + b) + c
+                for (b, c) in sums(egraph, rhs) {
+                    let ab = add(egraph, lhs, b);
+                    equivalent.push(add(egraph, ab, c));
+                }
+                equivalent
+            },
+        },
+        Rule {
+            name: "distribute",
+            apply: |egraph, node| {
+                if !node.is_product() {
+                    return vec![];
+                }
+                let [lhs, rhs] = node.children()[..] else {
+                    unreachable!()
+                };
+                let mut equivalent = vec![];
+                // a (b + c) => ab + ac
+                for (b, c) in sums(egraph, rhs) {
+                    if !node.fits(egraph, 1, [b, c]) {
+                        continue;
+                    }
+                    let ab = egraph.add(node.with_children(lhs, b));
+                    let ac = egraph.add(node.with_children(lhs, c));
+                    equivalent.push(add(egraph, ab, ac));
+                }
+                // (a + b) c => ac + bc
+                for (a, b) in sums(egraph, lhs) {
+                    if !node.fits(egraph, 0, [a, b]) {
+                        continue;
+                    }
+                    let ac = egraph.add(node.with_children(a, rhs));
+                    let bc = egraph.add(node.with_children(b, rhs));
+                    equivalent.push(add(egraph, ac, bc));
+                }
+                equivalent
+            },
+        },
+        Rule {
+            name: "factor",
+            apply: |egraph, node| {
+                let ENode::Elementwise {
+                    op: PrimitiveOp::Add,
+                    lhs,
+                    rhs,
+                } = *node
+                else {
+                    return vec![];
+                };
+                let products = |id| -> Vec<ENode> {
+                    egraph
+                        .nodes(id)
+                        .iter()
+                        .filter(|node| node.is_product())
+                        .cloned()
+                        .collect()
+                };
+                let (left, right) = (products(lhs), products(rhs));
+                let mut equivalent = vec![];
+                for p in &left {
+                    for q in &right {
+                        let ([a, b], [c, d]) = (
+                            <[Id; 2]>::try_from(p.children()).unwrap(),
+                            <[Id; 2]>::try_from(q.children()).unwrap(),
+                        );
+                        // Only the operands of the two products may differ.
+                        if p.with_children(a, a) != q.with_children(a, a) {
+                            continue;
+                        }
+                        // ab + ad => a (b + d)
+                        if a == c && p.fits(egraph, 1, [b, d]) {
+                            let bd = add(egraph, b, d);
+                            equivalent.push(egraph.add(p.with_children(a, bd)));
+                        }
+                        // ab + cb => (a + c) b
+                        if b == d && p.fits(egraph, 0, [a, c]) {
+                            let ac = add(egraph, a, c);
+                            equivalent.push(egraph.add(p.with_children(ac, b)));
+                        }
+                    }
+                }
+                equivalent
+            },
+        },
+    ]
+}
+
+/// An expression rewritten by `optimize`.
+#[derive(Debug, Clone)]
+pub struct Optimized {
+    pub expr: TensorExpr,
+    pub cost: usize,
+    /// Whether the rules found every equivalent expression. Otherwise `expr`
+    /// is the cheapest of those found before the graph grew too large.
+    pub saturated: bool,
+}
+
+/// Rewrites `expr` with `rules` to the equivalent expression `cost` prices
+/// cheapest, as priced by `EGraph::extract`.
+pub fn optimize(expr: &TensorExpr, cost: &impl Fn(&ENode, &[usize]) -> usize) -> Optimized {
+    let (egraph, root, saturated) = saturated(expr);
+    let (cost, expr) = egraph.extract(root, cost);
+    Optimized {
+        expr,
+        cost,
+        saturated,
+    }
+}
+
+/// Rewrites `expr` like `optimize`, to the equivalent expression that stages
+/// the fewest ops as priced by `staged_ops`.
+pub fn optimize_staged(expr: &TensorExpr) -> Optimized {
+    let (egraph, root, saturated) = saturated(expr);
+    let (cost, expr) = egraph.extract(root, &staged_ops(&egraph));
+    Optimized {
+        expr,
+        cost,
+        saturated,
+    }
+}
+
+/// An e-graph of the expressions `rules` find equal to `expr`, the class of
+/// `expr`, and whether it is saturated.
+fn saturated(expr: &TensorExpr) -> (EGraph, Id, bool) {
+    let mut egraph = EGraph::default();
+    let root = egraph.add_expr(expr);
+    let saturated = egraph.saturate(&rules());
+    (egraph, root, saturated)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        sam::{LevelFormat::Compressed, PrimitiveOp},
+        sym::ScopeRef,
+        tensor::InputTensor,
+        tensor_expr::TensorExpr,
+        test_util::{self, inputs, stage_and_execute},
+    };
+
+    use super::{ast_size, optimize, optimize_staged, rules, EGraph, ENode, Rule, MAX_NODES};
+
+    fn input(name: &str) -> TensorExpr {
+        TensorExpr::from(test_util::input(name))
+    }
+
+    /// Stages `expr` and counts the `SamOps` it takes, along with what they
+    /// compute.
+    fn run(expr: &TensorExpr) -> (usize, Vec<(Vec<usize>, f64)>) {
+        let scope = ScopeRef::default();
+        let output = expr.lower().unwrap();
+        let found = stage_and_execute(output, &[Compressed; 2], vec![3, 3], &inputs(), &scope);
+        let ops = scope.borrow().program_order().count();
+        (ops, found.to_coo())
+    }
+
+    #[test]
+    fn test_congruence() {
+        let mut egraph = EGraph::default();
+        let [a, b, c] = ["A", "B", "C"].map(|name| egraph.add_expr(&input(name)));
+        let ac = egraph.add_expr(&(input("A") + input("C")));
+        let bc = egraph.add_expr(&(input("B") + input("C")));
+        assert_ne!(egraph.find(ac), egraph.find(bc));
+        assert!(egraph.union(a, b));
+        egraph.rebuild();
+        assert_eq!(egraph.find(ac), egraph.find(bc));
+        assert!(!egraph.union(ac, bc));
+        assert_eq!(egraph.class_count(), 3);
+        assert_eq!(egraph.nodes(c).len(), 1);
+    }
+
+    #[test]
+    fn test_saturate() {
+        let [a, b, c] = ["A", "B", "C"].map(input);
+        let mut egraph = EGraph::default();
+        let root = egraph.add_expr(&(&a * &b + &a * &c));
+        assert!(egraph.saturate(&rules()));
+        let factored = egraph.add_expr(&(&a * (&b + &c)));
+        let commuted = egraph.add_expr(&((&c + &b) * &a));
+        assert_eq!(egraph.find(root), egraph.find(factored));
+        assert_eq!(egraph.find(root), egraph.find(commuted));
+        let (cost, expr) = egraph.extract(root, &ast_size);
+        assert_eq!(cost, 5);
+        assert_eq!(run(&expr).1, run(&(&a * &b + &a * &c)).1);
+    }
+
+    #[test]
+    fn test_node_limit() {
+        // Renames every input two ways, doubling the inputs found each time.
+        let rules = [Rule {
+            name: "rename",
+            apply: |egraph, node| {
+                let ENode::Input(input) = node else {
+                    return vec![];
+                };
+                ["a", "b"]
+                    .map(|suffix| {
+                        let mut renamed = input.clone();
+                        renamed.name.push_str(suffix);
+                        egraph.add(ENode::Input(renamed))
+                    })
+                    .to_vec()
+            },
+        }];
+        let mut egraph = EGraph::default();
+        egraph.add_expr(&input("A"));
+        assert!(!egraph.saturate(&rules));
+        // It stops at the first match taking it past the limit.
+        assert!((MAX_NODES + 1..=MAX_NODES + 2).contains(&egraph.node_count()));
+    }
+
+    #[test]
+    fn test_optimize_matmul() {
+        let [a, b, c] = ["A", "B", "C"].map(input);
+        // Weigh a matmul as ten elementwise ops.
+        let cost = |node: &ENode, children: &[usize]| {
+            let cost = match node {
+                ENode::Matmul { .. } => 10,
+                _ => 1,
+            };
+            cost + children.iter().sum::<usize>()
+        };
+        let expr = a.matmul(&b) + a.matmul(&c);
+        let optimized = optimize(&expr, &cost);
+        assert!(optimized.saturated);
+        assert_eq!(optimized.cost, 10 + 1 + 3);
+        let optimized = optimized.expr;
+        assert!(matches!(
+            &optimized,
+            TensorExpr::Matmul { lhs, rhs, .. } if **lhs == a && matches!(
+                rhs.as_ref(),
+                TensorExpr::Elementwise { op: PrimitiveOp::Add, .. }
+            )
+        ));
+        let (ops, result) = run(&expr);
+        let (optimized_ops, optimized_result) = run(&optimized);
+        assert!(optimized_ops < ops);
+        assert_eq!(optimized_result, result);
+    }
+
+    #[test]
+    fn test_optimize_staged() {
+        let [a, b, c] = ["A", "B", "C"].map(input);
+        let expr = a.matmul(&b) + a.matmul(&c);
+        let optimized = optimize_staged(&expr);
+        assert!(optimized.saturated);
+        assert!(matches!(
+            &optimized.expr,
+            TensorExpr::Matmul { lhs, rhs, .. } if **lhs == a && matches!(
+                rhs.as_ref(),
+                TensorExpr::Elementwise { op: PrimitiveOp::Add, .. }
+            )
+        ));
+        let (ops, result) = run(&expr);
+        let (optimized_ops, optimized_result) = run(&optimized.expr);
+        assert!(optimized_ops < ops);
+        assert_eq!(optimized_result, result);
+    }
+
+    #[test]
+    fn test_contract_over_sums() {
+        let tensor = |name: &str, order| {
+            TensorExpr::from(InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; order],
+            })
+        };
+        let contract = |rhs: &TensorExpr| TensorExpr::Contract {
+            lhs: Box::new(tensor("A", 2)),
+            lhs_indices: vec!['i', 'j'],
+            rhs: Box::new(rhs.clone()),
+            rhs_indices: vec!['j'],
+            output: vec!['i'],
+        };
+        let [u, v, m] = [tensor("u", 1), tensor("v", 1), tensor("M", 2)];
+        let mut egraph = EGraph::default();
+        let vectors = egraph.add_expr(&contract(&(&u + &v)));
+        let mixed = egraph.add_expr(&contract(&(&u + &m)));
+        assert!(egraph.saturate(&rules()));
+        // A sum of vectors is indexed by j alone, like each of its terms.
+        let distributed = egraph.add_expr(&(contract(&u) + contract(&v)));
+        assert_eq!(egraph.find(vectors), egraph.find(distributed));
+        // M is not, so the contraction is left over the sum.
+        let distributed = egraph.add_expr(&(contract(&u) + contract(&m)));
+        assert_ne!(egraph.find(mixed), egraph.find(distributed));
+    }
+}
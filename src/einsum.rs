@@ -0,0 +1,218 @@
+use crate::{
+    lower::{lower_with, IndexExpr, LowerError},
+    schedule::Schedule,
+    tensor::Tensor,
+};
+
+fn subscripts(word: &str) -> Result<Vec<char>, LowerError> {
+    let word = word.trim();
+    match word.chars().find(|c| !c.is_ascii_alphabetic()) {
+        Some(c) => Err(LowerError::Syntax(format!(
+            "unexpected {c:?} in subscripts {word:?}"
+        ))),
+        None => Ok(word.chars().collect()),
+    }
+}
+
+/// Builds the tensor described by an einsum specification such as
+/// `"ik,jk->ij"`, with one comma-separated subscript list per operand.
+///
+/// Each operand's subscripts name its levels in storage order. Indices missing
+/// from the output are summed over; without `->` the output is the indices
+/// that occur exactly once, sorted.
+pub fn einsum(spec: &str, operands: Vec<Tensor>) -> Result<Tensor, LowerError> {
+    einsum_with(spec, operands, &Schedule::default())
+}
+
+/// Builds the tensor described by `spec` like `einsum`, staging its loops as
+/// `schedule` says.
+pub fn einsum_with(
+    spec: &str,
+    operands: Vec<Tensor>,
+    schedule: &Schedule,
+) -> Result<Tensor, LowerError> {
+    let (inputs, output) = match spec.split_once("->") {
+        Some((inputs, output)) => (inputs, Some(subscripts(output)?)),
+        None => (spec, None),
+    };
+    let inputs = inputs
+        .split(',')
+        .map(subscripts)
+        .collect::<Result<Vec<_>, _>>()?;
+    if inputs.len() != operands.len() {
+        return Err(LowerError::Syntax(format!(
+            "{spec:?} has {} operands but {} were given",
+            inputs.len(),
+            operands.len()
+        )));
+    }
+    let output = output.unwrap_or_else(|| {
+        let all: Vec<char> = inputs.iter().flatten().copied().collect();
+        let mut once: Vec<char> = all
+            .iter()
+            .filter(|index| all.iter().filter(|other| other == index).count() == 1)
+            .copied()
+            .collect();
+        once.sort_unstable();
+        once
+    });
+    let expr = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(operand, indices)| IndexExpr::Access { operand, indices })
+        .reduce(|lhs, rhs| IndexExpr::Mul(Box::new(lhs), Box::new(rhs)))
+        .ok_or_else(|| LowerError::Syntax("no operands".to_string()))?;
+    lower_with(expr, operands, &output, schedule)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lower::LowerError,
+        sam::LevelFormat::{self, Compressed},
+        storage::SparseTensor,
+        sym::ScopeRef,
+        tensor::{InputTensor, Tensor},
+        test_util::{matrix_a, matrix_b, named, stage_and_execute},
+    };
+
+    use super::einsum;
+
+    fn input(name: &str, order: usize) -> Tensor {
+        InputTensor {
+            name: name.to_string(),
+            formats: vec![Compressed; order],
+        }
+        .stage()
+    }
+
+    fn run(
+        output: Tensor,
+        shape: Vec<usize>,
+        formats: &[LevelFormat],
+        inputs: Vec<(&'static str, SparseTensor)>,
+    ) -> Vec<(Vec<usize>, f64)> {
+        let scope = ScopeRef::default();
+        stage_and_execute(output, formats, shape, &named(inputs), &scope).to_coo()
+    }
+
+    #[test]
+    fn test_matmul() {
+        let output = einsum("ik,jk->ij", vec![input("A", 2), input("B", 2)]).unwrap();
+        let result = run(
+            output,
+            vec![3, 3],
+            &[Compressed; 2],
+            vec![("A", matrix_a()), ("B", matrix_b())],
+        );
+        assert_eq!(result, vec![(vec![0, 0], 4.0), (vec![0, 1], 10.0)]);
+    }
+
+    #[test]
+    fn test_hadamard() {
+        let output = einsum("ij,ij->ij", vec![input("A", 2), input("B", 2)]).unwrap();
+        let b = SparseTensor::from_coo(vec![3, 3], vec![(vec![0, 2], 4.0), (vec![2, 2], 5.0)]);
+        let result = run(
+            output,
+            vec![3, 3],
+            &[Compressed; 2],
+            vec![("A", matrix_a()), ("B", b)],
+        );
+        assert_eq!(result, vec![(vec![0, 2], 8.0)]);
+    }
+
+    #[test]
+    fn test_implicit_output() {
+        let output = einsum("ij,j", vec![input("A", 2), input("x", 1)]).unwrap();
+        let x = SparseTensor::from_coo(vec![3], vec![(vec![1], 2.0), (vec![2], 3.0)]);
+        let result = run(
+            output,
+            vec![3],
+            &[Compressed],
+            vec![("A", matrix_a()), ("x", x)],
+        );
+        assert_eq!(result, vec![(vec![0], 6.0), (vec![2], 6.0)]);
+    }
+
+    #[test]
+    fn test_mttkrp() {
+        let output = einsum(
+            "ijk,rj,rk->ir",
+            vec![input("T", 3), input("B", 2), input("C", 2)],
+        )
+        .unwrap();
+        let t = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![
+                (vec![0, 0, 1], 2.0),
+                (vec![0, 1, 1], 3.0),
+                (vec![1, 1, 0], 4.0),
+            ],
+        );
+        let b = SparseTensor::from_coo(vec![2, 2], vec![(vec![0, 0], 1.0), (vec![1, 1], 5.0)]);
+        let c = SparseTensor::from_coo(vec![2, 2], vec![(vec![0, 1], 7.0), (vec![1, 0], 1.0)]);
+        let result = run(
+            output,
+            vec![2, 2],
+            &[Compressed; 2],
+            vec![("T", t), ("B", b), ("C", c)],
+        );
+        // T[0][1][1] misses C[1][1], every other nonzero of T pairs up once.
+        assert_eq!(result, vec![(vec![0, 0], 14.0), (vec![1, 1], 20.0)]);
+    }
+
+    #[test]
+    fn test_merged_matmul() {
+        // B stored by rows puts k outside j, so rows of B are merged per row of A.
+        let output = einsum("ik,kj->ij", vec![input("A", 2), input("B", 2)]).unwrap();
+        let b = SparseTensor::from_coo(vec![3, 3], vec![(vec![0, 0], 4.0), (vec![2, 1], 5.0)]);
+        let result = run(
+            output,
+            vec![3, 3],
+            &[Compressed; 2],
+            vec![("A", matrix_a()), ("B", b)],
+        );
+        assert_eq!(result, vec![(vec![0, 0], 4.0), (vec![0, 1], 10.0)]);
+    }
+
+    #[test]
+    fn test_merged_mttkrp() {
+        let output = einsum(
+            "ijk,jr,kr->ir",
+            vec![input("T", 3), input("B", 2), input("C", 2)],
+        )
+        .unwrap();
+        let t = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![
+                (vec![0, 0, 1], 2.0),
+                (vec![0, 1, 1], 3.0),
+                (vec![1, 1, 0], 4.0),
+            ],
+        );
+        let b = SparseTensor::from_coo(vec![2, 2], vec![(vec![0, 0], 1.0), (vec![1, 1], 5.0)]);
+        let c = SparseTensor::from_coo(vec![2, 2], vec![(vec![1, 0], 7.0), (vec![0, 1], 1.0)]);
+        let result = run(
+            output,
+            vec![2, 2],
+            &[Compressed; 2],
+            vec![("T", t), ("B", b), ("C", c)],
+        );
+        // The same tensors as in `test_mttkrp`, with B and C stored transposed.
+        assert_eq!(result, vec![(vec![0, 0], 14.0), (vec![1, 1], 20.0)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |spec, order: &[usize]| {
+            let operands = order.iter().map(|&n| input("A", n)).collect();
+            einsum(spec, operands).err().unwrap()
+        };
+        assert!(matches!(err("i1,j->ij", &[2, 1]), LowerError::Syntax(_)));
+        assert!(matches!(err("ij,j->i", &[2]), LowerError::Syntax(_)));
+        assert!(matches!(err("ijk,j->i", &[2, 1]), LowerError::Rank { .. }));
+        assert_eq!(err("ii->i", &[2]), LowerError::RepeatedIndex('i'));
+        assert_eq!(err("ij->ik", &[2]), LowerError::UnboundIndex('k'));
+        assert!(matches!(err("ij->ji", &[2]), LowerError::LoopOrder(_)));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{
+    lower::{lower, IndexExpr, LowerError},
+    sam::PrimitiveOp,
+    sym::StageError,
+    tensor::Tensor,
+};
+
+/// Combines two tensors of the same rank entry by entry. Additive ops union
+/// the coordinates of every level, multiplicative ones intersect them.
+pub fn elementwise(op: PrimitiveOp, a: Tensor, b: Tensor) -> Result<Tensor, LowerError> {
+    // One letter per level, so only the first 26 levels can be indexed.
+    let indices: Vec<char> = ('a'..='z').take(a.meta.len()).collect();
+    if indices.len() < a.meta.len() {
+        return Err(LowerError::Rank {
+            operand: "operand 0".to_string(),
+            expected: a.meta.len(),
+            found: indices.len(),
+        });
+    }
+    let access = |operand| {
+        Box::new(IndexExpr::Access {
+            operand,
+            indices: indices.clone(),
+        })
+    };
+    let expr = match op {
+        PrimitiveOp::Add => IndexExpr::Add(access(0), access(1)),
+        PrimitiveOp::Mul => IndexExpr::Mul(access(0), access(1)),
+    };
+    lower(expr, vec![a, b], &indices)
+}
+
+/// Combines two tensors like `elementwise`, for the operators and `matadd`
+/// that return a `Tensor`: operands that cannot be combined give a tensor that
+/// fails to stage instead, with a `StageError::Rank` if their ranks differ.
+pub(crate) fn combine(op: PrimitiveOp, a: Tensor, b: Tensor) -> Tensor {
+    let (expected, found) = (a.meta.len(), b.meta.len());
+    elementwise(op, a, b).unwrap_or_else(|err| {
+        let err = match err {
+            LowerError::Rank { .. } if expected != found => StageError::Rank { expected, found },
+            err => StageError::Type {
+                op: format!("{op:?}"),
+                reason: err.to_string(),
+            },
+        };
+        Tensor::failed(err, expected)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lower::LowerError,
+        matadd::matadd,
+        sam::{LevelFormat::Compressed, PrimitiveOp, SamOps},
+        storage::SparseTensor,
+        sym::{Expr, ScopeRef, StageError},
+        tensor::InputTensor,
+        test_util::{named, stage_and_execute},
+    };
+
+    use super::elementwise;
+
+    fn run(
+        op: PrimitiveOp,
+        shape: Vec<usize>,
+        a: SparseTensor,
+        b: SparseTensor,
+    ) -> Vec<(Vec<usize>, f64)> {
+        let input = |name: &str| InputTensor {
+            name: name.to_string(),
+            formats: vec![Compressed; shape.len()],
+        };
+        let output = elementwise(op, input("A").stage(), input("B").stage()).unwrap();
+        let formats = vec![Compressed; shape.len()];
+        let inputs = named([("A", a), ("B", b)]);
+        stage_and_execute(output, &formats, shape, &inputs, &ScopeRef::default()).to_coo()
+    }
+
+    #[test]
+    fn test_vector() {
+        let a = SparseTensor::from_coo(vec![4], vec![(vec![0], 1.0), (vec![2], 2.0)]);
+        let b = SparseTensor::from_coo(vec![4], vec![(vec![2], 3.0), (vec![3], 4.0)]);
+        assert_eq!(
+            run(PrimitiveOp::Add, vec![4], a.clone(), b.clone()),
+            vec![(vec![0], 1.0), (vec![2], 5.0), (vec![3], 4.0)]
+        );
+        assert_eq!(run(PrimitiveOp::Mul, vec![4], a, b), vec![(vec![2], 6.0)]);
+    }
+
+    #[test]
+    fn test_3d() {
+        let a = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![
+                (vec![0, 0, 1], 1.0),
+                (vec![0, 1, 0], 2.0),
+                (vec![1, 1, 1], 3.0),
+            ],
+        );
+        let b = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![(vec![0, 0, 0], 4.0), (vec![1, 1, 1], 5.0)],
+        );
+        assert_eq!(
+            run(PrimitiveOp::Add, vec![2, 2, 2], a.clone(), b.clone()),
+            vec![
+                (vec![0, 0, 0], 4.0),
+                (vec![0, 0, 1], 1.0),
+                (vec![0, 1, 0], 2.0),
+                (vec![1, 1, 1], 8.0)
+            ]
+        );
+        // Row 0 of both only overlaps in empty intersections, so it is dropped.
+        assert_eq!(
+            run(PrimitiveOp::Mul, vec![2, 2, 2], a, b),
+            vec![(vec![1, 1, 1], 15.0)]
+        );
+    }
+
+    #[test]
+    fn test_nested_3d() {
+        let input = |name: &str| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; 3],
+            }
+            .stage()
+        };
+        let a = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![
+                (vec![0, 0, 1], 1.0),
+                (vec![0, 1, 0], 2.0),
+                (vec![1, 1, 1], 3.0),
+            ],
+        );
+        let b = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![(vec![0, 0, 0], 4.0), (vec![1, 1, 1], 5.0)],
+        );
+        let c = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![(vec![0, 0, 0], 7.0), (vec![1, 1, 1], 2.0)],
+        );
+        let inputs = named([("A", a), ("B", b), ("C", c)]);
+        // Row 0 of A * B is dropped along with the fibers of every level under
+        // it, so C only lines up with the rest.
+        let run = |op| {
+            let ab = elementwise(PrimitiveOp::Mul, input("A"), input("B")).unwrap();
+            let output = elementwise(op, ab, input("C")).unwrap();
+            let scope = ScopeRef::default();
+            stage_and_execute(output, &[Compressed; 3], vec![2, 2, 2], &inputs, &scope).to_coo()
+        };
+        assert_eq!(
+            run(PrimitiveOp::Add),
+            vec![(vec![0, 0, 0], 7.0), (vec![1, 1, 1], 17.0)]
+        );
+        assert_eq!(run(PrimitiveOp::Mul), vec![(vec![1, 1, 1], 30.0)]);
+    }
+
+    #[test]
+    fn test_commuted_operands_share_ops() {
+        let input = |name: &str| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; 2],
+            }
+            .stage()
+        };
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let ab = elementwise(PrimitiveOp::Mul, input("A"), input("B")).unwrap();
+        let ba = elementwise(PrimitiveOp::Mul, input("B"), input("A")).unwrap();
+        let writers = ab.stage_output("X", &[Compressed; 2], root, &scope);
+        let ops = scope.borrow().program_order().count();
+        // Only the writers differ.
+        ba.stage_output("Y", &[Compressed; 2], root, &scope);
+        assert_eq!(scope.borrow().program_order().count(), ops + writers.len());
+
+        let sum = elementwise(PrimitiveOp::Add, ab, ba).unwrap();
+        let a = SparseTensor::from_coo(vec![2, 2], vec![(vec![0, 1], 2.0), (vec![1, 1], 3.0)]);
+        let b = SparseTensor::from_coo(vec![2, 2], vec![(vec![1, 1], 4.0)]);
+        let inputs = named([("A", a), ("B", b)]);
+        let scope = ScopeRef::default();
+        let found = stage_and_execute(sum, &[Compressed; 2], vec![2, 2], &inputs, &scope);
+        assert_eq!(found.to_coo(), vec![(vec![1, 1], 24.0)]);
+    }
+
+    #[test]
+    fn test_rank_mismatch() {
+        let input = |name: &str, order| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; order],
+            }
+            .stage()
+        };
+        let err = elementwise(PrimitiveOp::Add, input("A", 2), input("x", 1)).err();
+        assert!(matches!(
+            err,
+            Some(LowerError::Rank {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        ));
+
+        // `matadd` only fails once staged.
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let sum = matadd(input("A", 2), input("x", 1));
+        assert_eq!(sum.meta.len(), 2);
+        assert_eq!(
+            (sum.comp)(root, &scope).err(),
+            Some(StageError::Rank {
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_high_rank() {
+        // Each level takes a letter of its own, `a` to `z`.
+        let input = |name: &str, order| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; order],
+            }
+            .stage()
+        };
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let output = elementwise(PrimitiveOp::Mul, input("A", 26), input("B", 26)).unwrap();
+        let writers = output.try_stage_output("X", &[Compressed; 26], root, &scope);
+        assert_eq!(writers.unwrap().len(), 27);
+
+        let err = elementwise(PrimitiveOp::Mul, input("A", 27), input("B", 27)).err();
+        assert_eq!(
+            err,
+            Some(LowerError::Rank {
+                operand: "operand 0".to_string(),
+                expected: 27,
+                found: 26,
+            })
+        );
+    }
+}
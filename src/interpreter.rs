@@ -0,0 +1,1266 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{
+    sam::{JoinType, LevelFormat, PrimitiveOp, SamOps},
+    storage::{Level, SparseTensor},
+    sym::{Expr, Scope, Sym},
+};
+
+/// A single element of a SAM stream.
+///
+/// Streams are flat token sequences: a stream of nesting depth `d` uses stop
+/// tokens `Stop(0)..Stop(d - 1)` to close fibers, where `Stop(k)` closes the
+/// innermost fiber along with `k` enclosing ones, and always ends with `Done`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Token {
+    Ref(usize),
+    Crd(usize),
+    Val(f64),
+    /// A reference to a fiber that one side of a union join does not have.
+    Empty,
+    Stop(usize),
+    Done,
+}
+
+impl Token {
+    fn is_stop(&self) -> bool {
+        matches!(self, Token::Stop(_))
+    }
+}
+
+pub type Stream = Vec<Token>;
+
+/// A port access made by an op while it was being evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    Read(usize),
+    Write(usize),
+}
+
+type Trace = RefCell<Vec<Event>>;
+
+/// A read cursor over an already evaluated stream.
+struct Input<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    port: usize,
+    trace: &'a Trace,
+}
+
+impl Input<'_> {
+    fn next(&mut self) -> Token {
+        let token = *self
+            .tokens
+            .get(self.pos)
+            .unwrap_or_else(|| panic!("Read past the end of a stream"));
+        self.pos += 1;
+        self.trace.borrow_mut().push(Event::Read(self.port));
+        token
+    }
+
+    /// Reads the remainder of the current fiber, returning its elements and the
+    /// token (a stop or `Done`) that terminated it.
+    fn fiber(&mut self) -> (Vec<Token>, Token) {
+        let mut elements = vec![];
+        loop {
+            match self.next() {
+                tok @ (Token::Stop(_) | Token::Done) => return (elements, tok),
+                tok => elements.push(tok),
+            }
+        }
+    }
+}
+
+struct Output<'a> {
+    tokens: Stream,
+    port: usize,
+    trace: &'a Trace,
+}
+
+impl Output<'_> {
+    fn push(&mut self, token: Token) {
+        self.tokens.push(token);
+        self.trace.borrow_mut().push(Event::Write(self.port));
+    }
+}
+
+/// Evaluates the ops of `scope` that `roots` depend on, reading tensor data by
+/// the names used in `Fiberlookup` and `Arrayval`.
+///
+/// Returns the stream computed for every live sym.
+pub fn interpret(
+    scope: &Scope<SamOps>,
+    roots: FxHashSet<Sym>,
+    tensors: &FxHashMap<String, SparseTensor>,
+) -> FxHashMap<Sym, Stream> {
+    evaluate(scope, roots, tensors, |_, _, _| {})
+}
+
+/// Evaluates the writers among `roots` and assembles the tensors they write,
+/// each with its shape in `shapes`. Positions of dense levels that no
+/// coordinate was written to hold zeros.
+pub fn execute(
+    scope: &Scope<SamOps>,
+    roots: FxHashSet<Sym>,
+    tensors: &FxHashMap<String, SparseTensor>,
+    shapes: &FxHashMap<String, Vec<usize>>,
+) -> FxHashMap<String, SparseTensor> {
+    let streams = interpret(scope, roots, tensors);
+    let mut levels: FxHashMap<&str, Vec<(usize, LevelFormat, &Stream)>> = FxHashMap::default();
+    let mut vals: FxHashMap<&str, &Stream> = FxHashMap::default();
+    for (expr, syms) in scope.program_order() {
+        if !streams.contains_key(&syms[0]) {
+            continue;
+        }
+        match expr {
+            SamOps::Fiberwrite {
+                coords,
+                tensor,
+                level,
+                format,
+            } => levels
+                .entry(tensor)
+                .or_default()
+                .push((*level, *format, &streams[coords])),
+            SamOps::Valwrite { values, tensor } => {
+                vals.insert(tensor, &streams[values]);
+            }
+            _ => {}
+        }
+    }
+    vals.into_iter()
+        .map(|(name, values)| {
+            let shape = shapes
+                .get(name)
+                .unwrap_or_else(|| panic!("No shape given for {name}"));
+            let mut written = levels.remove(name).unwrap_or_default();
+            written.sort_by_key(|(level, _, _)| *level);
+            assert_eq!(
+                written.len(),
+                shape.len(),
+                "Expected a level of {name} per dimension of {shape:?}"
+            );
+            let mut tensor = SparseTensor {
+                shape: shape.clone(),
+                levels: vec![],
+                vals: vec![],
+            };
+            // Where each position written to the level above is stored.
+            let mut stored = vec![0];
+            let mut positions = 1;
+            for (expected, ((level, format, coords), &size)) in
+                written.into_iter().zip(shape).enumerate()
+            {
+                assert_eq!(
+                    level, expected,
+                    "Level {expected} of {name} is never written"
+                );
+                let (written, below) = write_level(coords, format, size, &stored, positions);
+                positions = match written {
+                    Level::Dense { size } => positions * size,
+                    Level::Compressed { ref crd, .. } | Level::Singleton { ref crd } => crd.len(),
+                    Level::Bitvector { ref seg, .. } => *seg.last().unwrap(),
+                };
+                stored = below;
+                tensor.levels.push(written);
+            }
+            let values: Vec<_> = values
+                .iter()
+                .filter_map(|tok| match tok {
+                    Token::Val(v) => Some(*v),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                values.len(),
+                stored.len(),
+                "Tensor {name} has a different number of values than positions"
+            );
+            tensor.vals = vec![0.0; positions];
+            for (value, position) in values.into_iter().zip(stored) {
+                tensor.vals[position] = value;
+            }
+            (name.to_string(), tensor)
+        })
+        .collect()
+}
+
+/// Builds a level of dimension `size` from a coordinate stream with one fiber
+/// per position written to the level above, stored at `parents` among its
+/// `positions` positions. Returns the level and where each coordinate written
+/// to it is stored.
+fn write_level(
+    coords: &Stream,
+    format: LevelFormat,
+    size: usize,
+    parents: &[usize],
+    positions: usize,
+) -> (Level, Vec<usize>) {
+    // A stop right after another closes an empty fiber, which is ambiguous for
+    // `Stop(1..)`: it may also close an enclosing fiber that has no fibers in it.
+    let mut written = vec![];
+    let mut current = vec![];
+    let mut after_stop = true;
+    for token in coords {
+        match *token {
+            Token::Crd(c) => {
+                assert!(c < size, "Coordinate {c} is out of bounds of size {size}");
+                current.push(c);
+                after_stop = false;
+            }
+            Token::Stop(k) => {
+                written.push((std::mem::take(&mut current), k > 0 && after_stop));
+                after_stop = true;
+            }
+            Token::Done => break,
+            tok => panic!("Fiberwrite expected a coordinate, got {tok:?}"),
+        }
+    }
+    if written.iter().filter(|(_, ambiguous)| !ambiguous).count() == parents.len() {
+        written.retain(|(_, ambiguous)| !ambiguous);
+    }
+    assert_eq!(
+        written.len(),
+        parents.len(),
+        "Expected one written fiber per parent position"
+    );
+    // Positions of the level above that nothing was written under, which
+    // only dense levels have, get empty fibers.
+    let mut fibers = vec![vec![]; positions];
+    for ((fiber, _), &parent) in written.into_iter().zip(parents) {
+        fibers[parent] = fiber;
+    }
+    let mut seg = vec![0];
+    for fiber in &fibers {
+        seg.push(seg.last().unwrap() + fiber.len());
+    }
+    let level = match format {
+        LevelFormat::Dense => Level::Dense { size },
+        LevelFormat::Compressed => Level::Compressed {
+            seg: seg.clone(),
+            crd: fibers.concat(),
+        },
+        LevelFormat::Singleton => {
+            assert!(
+                fibers.iter().all(|fiber| fiber.len() == 1),
+                "Singleton level needs exactly one coordinate per fiber"
+            );
+            Level::Singleton {
+                crd: fibers.concat(),
+            }
+        }
+        LevelFormat::Bitvector => {
+            let mut bits = vec![0u64; (positions * size).div_ceil(64)];
+            for (parent, fiber) in fibers.iter().enumerate() {
+                for c in fiber {
+                    let bit = parent * size + c;
+                    bits[bit / 64] |= 1 << (bit % 64);
+                }
+            }
+            Level::Bitvector {
+                size,
+                seg: seg.clone(),
+                bits,
+            }
+        }
+    };
+    let stored = parents
+        .iter()
+        .flat_map(|&parent| {
+            let seg = &seg;
+            fibers[parent]
+                .iter()
+                .enumerate()
+                .map(move |(i, c)| match format {
+                    LevelFormat::Dense => parent * size + c,
+                    _ => seg[parent] + i,
+                })
+        })
+        .collect();
+    (level, stored)
+}
+
+/// Evaluates the live ops of `scope` in program order, handing `visit` the order
+/// in which each op accessed its ports (inputs numbered as in `Expr::inputs`).
+pub(crate) fn evaluate(
+    scope: &Scope<SamOps>,
+    roots: FxHashSet<Sym>,
+    tensors: &FxHashMap<String, SparseTensor>,
+    mut visit: impl FnMut(&SamOps, &[Sym], Vec<Event>),
+) -> FxHashMap<Sym, Stream> {
+    let live = scope.calculate_live_syms(roots);
+    let mut streams: FxHashMap<Sym, Stream> = FxHashMap::default();
+    for (expr, syms) in scope.program_order() {
+        if !syms.iter().any(|sym| live.contains(sym)) {
+            continue;
+        }
+        let trace = Trace::default();
+        let mut inputs = expr
+            .inputs()
+            .into_iter()
+            .enumerate()
+            .map(|(port, sym)| Input {
+                tokens: &streams[&sym],
+                pos: 0,
+                port,
+                trace: &trace,
+            })
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut input = || inputs.next().unwrap();
+        let mut outputs: Vec<_> = (0..syms.len())
+            .map(|port| Output {
+                tokens: vec![],
+                port,
+                trace: &trace,
+            })
+            .collect();
+        match expr {
+            SamOps::Root => {
+                outputs[0].push(Token::Ref(0));
+                outputs[0].push(Token::Done);
+            }
+            SamOps::Fiberlookup {
+                tensor: name,
+                level,
+                format,
+                ..
+            } => {
+                let tensor = lookup_tensor(tensors, name);
+                assert_eq!(
+                    tensor.levels[*level].format(),
+                    *format,
+                    "Level {level} of tensor {name} is stored in a different format"
+                );
+                fiberlookup(input(), tensor, *level, &mut outputs);
+            }
+            SamOps::Repeat { target, repeat } => {
+                let depth = |sym: &Sym| scope.stream_type(*sym).unwrap().depth;
+                let delta = depth(repeat) - depth(target);
+                self::repeat(input(), input(), delta, &mut outputs[0]);
+            }
+            SamOps::Arrayval { tensor, .. } => {
+                arrayval(input(), lookup_tensor(tensors, tensor), &mut outputs[0]);
+            }
+            SamOps::Join { tp, .. } => {
+                join([input(), input()], [input(), input()], *tp, &mut outputs);
+            }
+            SamOps::Reduce { op, keep_empty, .. } => {
+                reduce(input(), *op, *keep_empty, &mut outputs[0]);
+            }
+            SamOps::ALU { op, inputs: syms } => {
+                alu(syms.iter().map(|_| input()).collect(), *op, &mut outputs[0]);
+            }
+            SamOps::CoordDrop { values, .. } => {
+                let (inner, outer) = (input(), input());
+                coord_drop(inner, outer, values.map(|_| input()), &mut outputs);
+            }
+            SamOps::Spacc { crds, .. } => {
+                let crds = crds.iter().map(|_| input()).collect();
+                spacc(crds, input(), &mut outputs);
+            }
+            SamOps::Split { factor, .. } => {
+                split(input(), *factor, &mut outputs);
+            }
+            SamOps::Tilelookup { factor, .. } => {
+                let refs = input();
+                tilelookup(refs, [input(), input()], *factor, &mut outputs);
+            }
+            SamOps::Streamlookup { outer, .. } => {
+                let refs = input();
+                let outer = outer.map(|_| input());
+                streamlookup(refs, outer, input(), &mut outputs);
+            }
+            SamOps::Vallookup { .. } => {
+                vallookup(input(), input(), &mut outputs[0]);
+            }
+            SamOps::Empty { .. } => {
+                empty(input(), &mut outputs[0]);
+            }
+            SamOps::Constant { value, .. } => {
+                constant(input(), value.get(), &mut outputs[0]);
+            }
+            SamOps::Parallel { .. } => {
+                let mut input = input();
+                loop {
+                    let token = input.next();
+                    outputs[0].push(token);
+                    if token == Token::Done {
+                        break;
+                    }
+                }
+            }
+            SamOps::Genref { .. } => {
+                genref(input(), &mut outputs[0]);
+            }
+            SamOps::Fiberwrite { .. } | SamOps::Valwrite { .. } => {
+                let mut input = input();
+                while input.next() != Token::Done {}
+                outputs[0].push(Token::Done);
+            }
+        };
+        for (sym, output) in syms.iter().zip(outputs) {
+            streams.insert(*sym, output.tokens);
+        }
+        visit(expr, syms, trace.into_inner());
+    }
+    streams
+}
+
+fn lookup_tensor<'a>(tensors: &'a FxHashMap<String, SparseTensor>, name: &str) -> &'a SparseTensor {
+    tensors
+        .get(name)
+        .unwrap_or_else(|| panic!("No data bound for tensor {name}"))
+}
+
+fn apply(op: PrimitiveOp, lhs: f64, rhs: f64) -> f64 {
+    match op {
+        PrimitiveOp::Mul => lhs * rhs,
+        PrimitiveOp::Add => lhs + rhs,
+    }
+}
+
+fn fiberlookup(mut refs: Input, tensor: &SparseTensor, level: usize, out: &mut [Output]) {
+    let push = |out: &mut [Output], r, c| {
+        out[0].push(r);
+        out[1].push(c);
+    };
+    // Each fiber is closed by a stop that may still be promoted by an input stop.
+    let mut open = false;
+    loop {
+        let token = refs.next();
+        if open && !token.is_stop() {
+            push(out, Token::Stop(0), Token::Stop(0));
+        }
+        match token {
+            Token::Ref(reference) => {
+                for (crd, child) in tensor.fiber(level, reference) {
+                    push(out, Token::Ref(child), Token::Crd(crd));
+                }
+                open = true;
+            }
+            Token::Empty => open = true,
+            Token::Stop(k) => {
+                push(out, Token::Stop(k + 1), Token::Stop(k + 1));
+                open = false;
+            }
+            Token::Done => return push(out, Token::Done, Token::Done),
+            tok => panic!("Fiberlookup expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+fn arrayval(mut refs: Input, tensor: &SparseTensor, out: &mut Output) {
+    loop {
+        match refs.next() {
+            Token::Ref(reference) => out.push(Token::Val(tensor.value(reference))),
+            Token::Empty => out.push(Token::Val(0.0)),
+            tok @ Token::Stop(_) => out.push(tok),
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("Arrayval expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+/// Broadcasts each element of `target` over the matching subtree of `repeat`,
+/// which is nested `delta` levels deeper.
+fn repeat(mut target: Input, mut repeat: Input, delta: usize, out: &mut Output) {
+    let mut fiber_empty = true;
+    loop {
+        match target.next() {
+            Token::Done => {
+                while repeat.next() != Token::Done {}
+                return out.push(Token::Done);
+            }
+            Token::Stop(_) if delta == 0 => out.push(repeat.next()),
+            Token::Stop(_) => {
+                // A non-empty target fiber was already closed by the repeat stream.
+                if fiber_empty {
+                    out.push(repeat.next());
+                }
+                fiber_empty = true;
+            }
+            element if delta == 0 => {
+                repeat.next();
+                out.push(element);
+            }
+            element => {
+                fiber_empty = false;
+                loop {
+                    match repeat.next() {
+                        Token::Stop(k) => {
+                            out.push(Token::Stop(k));
+                            if k + 1 >= delta {
+                                break;
+                            }
+                        }
+                        Token::Done => panic!("Repeat stream ended before its target"),
+                        _ => out.push(element),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn join(mut refs: [Input; 2], mut crds: [Input; 2], tp: JoinType, out: &mut [Output]) {
+    let mut advance = |side: usize| (crds[side].next(), refs[side].next());
+    let (mut head1, mut head2) = (advance(0), advance(1));
+    loop {
+        let (r1, r2, crd) = match (head1.0, head2.0) {
+            (Token::Crd(c1), Token::Crd(c2)) if c1 == c2 => {
+                let refs = (head1.1, head2.1);
+                head1 = advance(0);
+                head2 = advance(1);
+                (refs.0, refs.1, c1)
+            }
+            (Token::Crd(c1), other) if !matches!(other, Token::Crd(c2) if c2 < c1) => {
+                let r1 = head1.1;
+                head1 = advance(0);
+                (r1, Token::Empty, c1)
+            }
+            (_, Token::Crd(c2)) => {
+                let r2 = head2.1;
+                head2 = advance(1);
+                (Token::Empty, r2, c2)
+            }
+            (end @ (Token::Stop(_) | Token::Done), _) => {
+                for port in out.iter_mut() {
+                    port.push(end);
+                }
+                if end == Token::Done {
+                    return;
+                }
+                head1 = advance(0);
+                head2 = advance(1);
+                continue;
+            }
+            (tok, _) => panic!("Join expected a coordinate, got {tok:?}"),
+        };
+        if tp == JoinType::Union || (r1 != Token::Empty && r2 != Token::Empty) {
+            out[0].push(r1);
+            out[1].push(r2);
+            out[2].push(Token::Crd(crd));
+        }
+    }
+}
+
+fn reduce(mut inputs: Input, op: PrimitiveOp, keep_empty: bool, out: &mut Output) {
+    let mut acc = None;
+    loop {
+        match inputs.next() {
+            Token::Val(v) => acc = Some(acc.map_or(v, |acc| apply(op, acc, v))),
+            Token::Stop(k) => {
+                // Unless kept, empty fibers produce no value at all.
+                match acc.take() {
+                    Some(acc) => out.push(Token::Val(acc)),
+                    None if keep_empty => out.push(Token::Val(op.identity())),
+                    None => {}
+                }
+                if k > 0 {
+                    out.push(Token::Stop(k - 1));
+                }
+            }
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("Reduce expected a value, got {tok:?}"),
+        }
+    }
+}
+
+fn alu(mut inputs: Vec<Input>, op: PrimitiveOp, out: &mut Output) {
+    loop {
+        let tokens: Vec<_> = inputs.iter_mut().map(Input::next).collect();
+        match tokens[0] {
+            Token::Val(_) => {
+                let result = tokens
+                    .iter()
+                    .map(|tok| match tok {
+                        Token::Val(v) => *v,
+                        tok => panic!("ALU inputs are misaligned, got {tok:?}"),
+                    })
+                    .reduce(|acc, v| apply(op, acc, v))
+                    .unwrap();
+                out.push(Token::Val(result));
+            }
+            tok @ Token::Stop(_) => out.push(tok),
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("ALU expected a value, got {tok:?}"),
+        }
+    }
+}
+
+fn coord_drop(mut inner: Input, mut outer: Input, mut values: Option<Input>, out: &mut [Output]) {
+    let mut fiber_empty = true;
+    // Whether any inner fiber was kept yet, whose stop then absorbs the stops
+    // of the fibers dropped after it; before one is, the highest of them is
+    // held back in case every fiber gets dropped.
+    let mut kept = false;
+    let mut held = 0;
+    loop {
+        match outer.next() {
+            tok @ Token::Crd(_) => {
+                let (elements, end) = inner.fiber();
+                let fiber = values.as_mut().map(Input::fiber);
+                if let Some((vals, _)) = &fiber {
+                    assert_eq!(
+                        vals.len(),
+                        elements.len(),
+                        "CoordDrop values are misaligned with the inner fibers"
+                    );
+                }
+                if !elements.is_empty() {
+                    out[0].push(tok);
+                    for element in elements {
+                        out[1].push(element);
+                    }
+                    out[1].push(end);
+                    if let Some((vals, end)) = fiber {
+                        for val in vals {
+                            out[2].push(val);
+                        }
+                        out[2].push(end);
+                    }
+                    kept = true;
+                } else if let Token::Stop(k) = end {
+                    absorb(k, kept, &mut held, out);
+                }
+                fiber_empty = false;
+            }
+            tok @ Token::Stop(_) => {
+                // An empty outer fiber shows up in the inner stream as a bare
+                // stop, which is dropped like an empty fiber.
+                if fiber_empty {
+                    values.as_mut().map(Input::next);
+                    if let Token::Stop(k) = inner.next() {
+                        absorb(k, kept, &mut held, out);
+                    }
+                }
+                out[0].push(tok);
+                fiber_empty = true;
+            }
+            Token::Done => {
+                while inner.next() != Token::Done {}
+                if let Some(values) = &mut values {
+                    while values.next() != Token::Done {}
+                }
+                out[0].push(Token::Done);
+                // With every fiber dropped, a bare stop is left for the empty
+                // fiber of the outermost level.
+                for output in &mut out[1..] {
+                    if !kept {
+                        output.push(Token::Stop(held));
+                    }
+                    output.push(Token::Done);
+                }
+                return;
+            }
+            tok => panic!("CoordDrop expected a coordinate, got {tok:?}"),
+        }
+    }
+}
+
+/// Drops the fiber closed by `Stop(k)` from the inner outputs of `coord_drop`.
+fn absorb(k: usize, kept: bool, held: &mut usize, out: &mut [Output]) {
+    if !kept {
+        *held = (*held).max(k);
+        return;
+    }
+    for output in &mut out[1..] {
+        if let Some(Token::Stop(last)) = output.tokens.last_mut() {
+            *last = (*last).max(k);
+        }
+    }
+}
+
+/// A fiber read back from a stream, for ops that work on whole fibers.
+enum Nested {
+    Elem(Token),
+    Fiber(Vec<Nested>),
+}
+
+impl Nested {
+    fn children(&self) -> &[Nested] {
+        match self {
+            Nested::Fiber(children) => children,
+            Nested::Elem(tok) => panic!("Expected a fiber, got {tok:?}"),
+        }
+    }
+
+    /// Writes out this fiber of `height` nested levels, closed by a stop that
+    /// also closes `extra` enclosing fibers.
+    fn emit(&self, height: usize, extra: usize, out: &mut Output) {
+        match self {
+            Nested::Elem(tok) => out.push(*tok),
+            Nested::Fiber(children) => match children.split_last() {
+                // The last child's closing stop doubles as this fiber's.
+                Some((last, rest)) if height > 1 => {
+                    for child in rest {
+                        child.emit(height - 1, 0, out);
+                    }
+                    last.emit(height - 1, extra + 1, out);
+                }
+                _ => {
+                    for child in children {
+                        child.emit(0, 0, out);
+                    }
+                    out.push(Token::Stop(height - 1 + extra));
+                }
+            },
+        }
+    }
+}
+
+impl Input<'_> {
+    /// Reads the next fiber of `height` nested levels along with the number of
+    /// enclosing fibers its closing stop also closed, or `None` at the end.
+    fn nested(&mut self, height: usize) -> Option<(Nested, usize)> {
+        let mut stack: Vec<Vec<Nested>> = (0..height).map(|_| vec![]).collect();
+        loop {
+            match self.next() {
+                Token::Stop(k) => {
+                    for level in (height.saturating_sub(k + 1)..height).rev() {
+                        let fiber = Nested::Fiber(std::mem::take(&mut stack[level]));
+                        match level {
+                            0 => return Some((fiber, (k + 1).saturating_sub(height))),
+                            _ => stack[level - 1].push(fiber),
+                        }
+                    }
+                }
+                Token::Done => {
+                    assert!(
+                        stack.iter().all(Vec::is_empty),
+                        "Stream ended inside a fiber"
+                    );
+                    return None;
+                }
+                tok => stack[height - 1].push(Nested::Elem(tok)),
+            }
+        }
+    }
+}
+
+fn spacc(mut crds: Vec<Input>, mut values: Input, out: &mut [Output]) {
+    let order = crds.len();
+    // Accumulates one fiber of the reduced level at a time.
+    while let Some((vals, extra)) = values.nested(order + 1) {
+        let levels: Vec<Nested> = crds
+            .iter_mut()
+            .enumerate()
+            .map(|(level, crds)| {
+                let (fiber, _) = crds.nested(level + 2).expect("Spacc inputs are misaligned");
+                fiber
+            })
+            .collect();
+        let mut sums = BTreeMap::new();
+        for (entry, vals) in vals.children().iter().enumerate() {
+            let crds: Vec<&Nested> = levels
+                .iter()
+                .map(|level| &level.children()[entry])
+                .collect();
+            accumulate(vals, &crds, &mut vec![], &mut sums);
+        }
+        let sums: Vec<(Vec<usize>, f64)> = sums.into_iter().collect();
+        for (level, out) in out[..order].iter_mut().enumerate() {
+            merged(&sums, 0, level, false).emit(level + 1, extra, out);
+        }
+        merged(&sums, 0, order - 1, true).emit(order, extra, &mut out[order]);
+    }
+    for crds in &mut crds {
+        assert!(crds.nested(1).is_none(), "Spacc inputs are misaligned");
+    }
+    for out in out {
+        out.push(Token::Done);
+    }
+}
+
+/// Adds the values of `vals` into `sums`, keyed by their coordinates in each
+/// of `crds` after `prefix`.
+fn accumulate(
+    vals: &Nested,
+    crds: &[&Nested],
+    prefix: &mut Vec<usize>,
+    sums: &mut BTreeMap<Vec<usize>, f64>,
+) {
+    let (level, inner) = crds.split_first().unwrap();
+    for (position, crd) in level.children().iter().enumerate() {
+        let Nested::Elem(Token::Crd(crd)) = crd else {
+            panic!("Spacc expected a coordinate");
+        };
+        prefix.push(*crd);
+        let val = &vals.children()[position];
+        if inner.is_empty() {
+            let Nested::Elem(Token::Val(val)) = val else {
+                panic!("Spacc expected a value");
+            };
+            *sums.entry(prefix.clone()).or_insert(0.0) += val;
+        } else {
+            let inner: Vec<&Nested> = inner
+                .iter()
+                .map(|level| &level.children()[position])
+                .collect();
+            accumulate(val, &inner, prefix, sums);
+        }
+        prefix.pop();
+    }
+}
+
+/// The fiber tree of sorted `sums` down to coordinate `level`, holding the
+/// coordinates of that level or, if `values`, the sums.
+fn merged(sums: &[(Vec<usize>, f64)], pos: usize, level: usize, values: bool) -> Nested {
+    Nested::Fiber(
+        sums.chunk_by(|(lhs, _), (rhs, _)| lhs[pos] == rhs[pos])
+            .map(|run| match (pos < level, values) {
+                (true, _) => merged(run, pos + 1, level, values),
+                (false, true) => Nested::Elem(Token::Val(run[0].1)),
+                (false, false) => Nested::Elem(Token::Crd(run[0].0[pos])),
+            })
+            .collect(),
+    )
+}
+
+fn split(mut crds: Input, factor: usize, out: &mut [Output]) {
+    let mut tiles = 0;
+    let mut last = None;
+    loop {
+        match crds.next() {
+            Token::Crd(crd) => {
+                // Coordinates are sorted, so a tile's are consecutive.
+                if last != Some(crd / factor) {
+                    out[0].push(Token::Ref(tiles));
+                    out[1].push(Token::Crd(crd / factor));
+                    tiles += 1;
+                    last = Some(crd / factor);
+                }
+            }
+            tok @ Token::Stop(_) => {
+                out[0].push(tok);
+                out[1].push(tok);
+                last = None;
+            }
+            Token::Done => {
+                out[0].push(Token::Done);
+                return out[1].push(Token::Done);
+            }
+            tok => panic!("Split expected a coordinate, got {tok:?}"),
+        }
+    }
+}
+
+/// Buffers the tiles of `level`, numbered as `split` numbers them, and looks
+/// them up like `fiberlookup` looks up fibers.
+fn tilelookup(mut refs: Input, level: [Input; 2], factor: usize, out: &mut [Output]) {
+    let [mut level_refs, mut level_crds] = level;
+    let mut tiles: Vec<Vec<(Token, Token)>> = vec![];
+    let mut last = None;
+    loop {
+        match (level_refs.next(), level_crds.next()) {
+            (reference, Token::Crd(crd)) => {
+                if last != Some(crd / factor) {
+                    tiles.push(vec![]);
+                    last = Some(crd / factor);
+                }
+                tiles.last_mut().unwrap().push((reference, Token::Crd(crd)));
+            }
+            (_, Token::Stop(_)) => last = None,
+            (_, Token::Done) => break,
+            (_, tok) => panic!("Tilelookup expected a coordinate, got {tok:?}"),
+        }
+    }
+    let push = |out: &mut [Output], r, c| {
+        out[0].push(r);
+        out[1].push(c);
+    };
+    let mut open = false;
+    loop {
+        let token = refs.next();
+        if open && !token.is_stop() {
+            push(out, Token::Stop(0), Token::Stop(0));
+        }
+        match token {
+            Token::Ref(tile) => {
+                for (reference, crd) in &tiles[tile] {
+                    push(out, *reference, *crd);
+                }
+                open = true;
+            }
+            Token::Empty => open = true,
+            Token::Stop(k) => {
+                push(out, Token::Stop(k + 1), Token::Stop(k + 1));
+                open = false;
+            }
+            Token::Done => return push(out, Token::Done, Token::Done),
+            tok => panic!("Tilelookup expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+/// Buffers the fibers of `crds`, one per coordinate of `outer` or a single one
+/// without it, and looks them up like `fiberlookup` looks up stored fibers.
+/// Element references number the coordinates of `crds` in order, as `genref`
+/// does.
+fn streamlookup(mut refs: Input, outer: Option<Input>, mut crds: Input, out: &mut [Output]) {
+    let mut fibers = vec![];
+    match outer {
+        None => {
+            let (elements, _) = crds.fiber();
+            fibers.push(elements);
+            while crds.next() != Token::Done {}
+        }
+        Some(mut outer) => {
+            let mut fiber_empty = true;
+            loop {
+                match outer.next() {
+                    Token::Crd(_) => {
+                        fibers.push(crds.fiber().0);
+                        fiber_empty = false;
+                    }
+                    Token::Stop(_) => {
+                        // An empty outer fiber shows up in `crds` as a bare stop.
+                        if fiber_empty {
+                            crds.next();
+                        }
+                        fiber_empty = true;
+                    }
+                    Token::Done => {
+                        while crds.next() != Token::Done {}
+                        break;
+                    }
+                    tok => panic!("Streamlookup expected a coordinate, got {tok:?}"),
+                }
+            }
+        }
+    }
+    let starts: Vec<usize> = fibers
+        .iter()
+        .scan(0, |start, fiber| {
+            *start += fiber.len();
+            Some(*start - fiber.len())
+        })
+        .collect();
+    let push = |out: &mut [Output], r, c| {
+        out[0].push(r);
+        out[1].push(c);
+    };
+    let mut open = false;
+    loop {
+        let token = refs.next();
+        if open && !token.is_stop() {
+            push(out, Token::Stop(0), Token::Stop(0));
+        }
+        match token {
+            Token::Ref(fiber) => {
+                for (position, crd) in fibers[fiber].iter().enumerate() {
+                    push(out, Token::Ref(starts[fiber] + position), *crd);
+                }
+                open = true;
+            }
+            Token::Empty => open = true,
+            Token::Stop(k) => {
+                push(out, Token::Stop(k + 1), Token::Stop(k + 1));
+                open = false;
+            }
+            Token::Done => return push(out, Token::Done, Token::Done),
+            tok => panic!("Streamlookup expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+/// Buffers `values` and looks them up like `arrayval` looks up stored ones.
+fn vallookup(mut refs: Input, mut values: Input, out: &mut Output) {
+    let mut buffered = vec![];
+    loop {
+        match values.next() {
+            Token::Val(v) => buffered.push(v),
+            Token::Stop(_) => {}
+            Token::Done => break,
+            tok => panic!("Vallookup expected a value, got {tok:?}"),
+        }
+    }
+    loop {
+        match refs.next() {
+            Token::Ref(reference) => out.push(Token::Val(buffered[reference])),
+            Token::Empty => out.push(Token::Val(0.0)),
+            tok @ Token::Stop(_) => out.push(tok),
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("Vallookup expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+fn empty(mut refs: Input, out: &mut Output) {
+    loop {
+        match refs.next() {
+            Token::Ref(_) | Token::Empty => out.push(Token::Empty),
+            tok @ Token::Stop(_) => out.push(tok),
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("Empty expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+fn constant(mut refs: Input, value: f64, out: &mut Output) {
+    loop {
+        match refs.next() {
+            Token::Ref(_) | Token::Empty => out.push(Token::Val(value)),
+            tok @ Token::Stop(_) => out.push(tok),
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("Constant expected a reference, got {tok:?}"),
+        }
+    }
+}
+
+fn genref(mut coords: Input, out: &mut Output) {
+    let mut next = 0;
+    loop {
+        match coords.next() {
+            Token::Crd(_) => {
+                out.push(Token::Ref(next));
+                next += 1;
+            }
+            tok @ Token::Stop(_) => out.push(tok),
+            Token::Done => return out.push(Token::Done),
+            tok => panic!("Genref expected a coordinate, got {tok:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        matadd::matadd,
+        matmul::matmul,
+        sam::{LevelFormat, SamOps},
+        storage::SparseTensor,
+        sym::{Expr, ScopeRef},
+        tensor::InputTensor,
+    };
+
+    use crate::test_util::{input, inputs, matrix_a, run, stage_and_execute};
+
+    use super::{interpret, Token};
+
+    use LevelFormat::{Compressed, Dense};
+    use Token::{Crd, Done, Stop, Val};
+
+    #[test]
+    fn test_scan() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let a = input("A").stage();
+        let (r0, c0) = (a.meta[0])(root, &scope).unwrap();
+        let (r1, c1) = (a.meta[1])(r0, &scope).unwrap();
+        let vals = (a.comp)(r1, &scope).unwrap();
+
+        let streams = interpret(
+            &scope.borrow(),
+            [c0, c1, vals].into_iter().collect(),
+            &inputs(),
+        );
+        assert_eq!(streams[&c0], vec![Crd(0), Crd(2), Stop(0), Done]);
+        assert_eq!(
+            streams[&c1],
+            vec![Crd(0), Crd(2), Stop(0), Crd(1), Stop(1), Done]
+        );
+        assert_eq!(
+            streams[&vals],
+            vec![Val(1.0), Val(2.0), Stop(0), Val(3.0), Stop(1), Done]
+        );
+    }
+
+    #[test]
+    fn test_buffered_lookups() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let a = input("A").stage();
+        let (r0, c0) = (a.meta[0])(root, &scope).unwrap();
+        let (r1, c1) = (a.meta[1])(r0, &scope).unwrap();
+        let vals = (a.comp)(r1, &scope).unwrap();
+        // Look the scanned streams up again as if they had been computed.
+        let [b0, d0] = SamOps::Streamlookup {
+            reference: root,
+            outer: None,
+            crds: c0,
+        }
+        .try_stage_n(&scope)
+        .unwrap();
+        let [b1, d1] = SamOps::Streamlookup {
+            reference: b0,
+            outer: Some(c0),
+            crds: c1,
+        }
+        .try_stage_n(&scope)
+        .unwrap();
+        let [found] = SamOps::Vallookup {
+            reference: b1,
+            values: vals,
+        }
+        .try_stage_n(&scope)
+        .unwrap();
+
+        let streams = interpret(
+            &scope.borrow(),
+            [c0, c1, vals, d0, d1, found].into_iter().collect(),
+            &inputs(),
+        );
+        assert_eq!(streams[&d0], streams[&c0]);
+        assert_eq!(streams[&d1], streams[&c1]);
+        assert_eq!(streams[&found], streams[&vals]);
+    }
+
+    #[test]
+    fn test_scan_csr() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let a = InputTensor {
+            name: "A".to_string(),
+            formats: vec![Dense, Compressed],
+        }
+        .stage();
+        let (r0, c0) = (a.meta[0])(root, &scope).unwrap();
+        let (_, c1) = (a.meta[1])(r0, &scope).unwrap();
+
+        let csr = SparseTensor::from_coo_with_formats(
+            vec![3, 3],
+            &[Dense, Compressed],
+            matrix_a().to_coo(),
+        );
+        let streams = interpret(
+            &scope.borrow(),
+            [c0, c1].into_iter().collect(),
+            &[("A".to_string(), csr)].into_iter().collect(),
+        );
+        assert_eq!(streams[&c0], vec![Crd(0), Crd(1), Crd(2), Stop(0), Done]);
+        assert_eq!(
+            streams[&c1],
+            vec![Crd(0), Crd(2), Stop(0), Stop(0), Crd(1), Stop(1), Done]
+        );
+    }
+
+    #[test]
+    fn test_matadd() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let output = matadd(input("A").stage(), input("B").stage());
+        let (_, c0) = (output.meta[0])(root, &scope).unwrap();
+        let (_, c1) = (output.meta[1])(root, &scope).unwrap();
+        let vals = (output.comp)(root, &scope).unwrap();
+
+        let streams = interpret(
+            &scope.borrow(),
+            [c0, c1, vals].into_iter().collect(),
+            &inputs(),
+        );
+        assert_eq!(streams[&c0], vec![Crd(0), Crd(1), Crd(2), Stop(0), Done]);
+        assert_eq!(
+            streams[&c1],
+            vec![
+                Crd(0),
+                Crd(2),
+                Stop(0),
+                Crd(2),
+                Stop(0),
+                Crd(1),
+                Stop(1),
+                Done
+            ]
+        );
+        assert_eq!(
+            streams[&vals],
+            vec![
+                Val(5.0),
+                Val(2.0),
+                Stop(0),
+                Val(5.0),
+                Stop(0),
+                Val(3.0),
+                Stop(1),
+                Done
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matmul() {
+        // B is stored transposed, so the output is A * B^T.
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let output = matmul(input("A").stage(), input("B").stage());
+        let (_, c0) = (output.meta[0])(root, &scope).unwrap();
+        let (_, c1) = (output.meta[1])(root, &scope).unwrap();
+        let vals = (output.comp)(root, &scope).unwrap();
+
+        let streams = interpret(
+            &scope.borrow(),
+            [c0, c1, vals].into_iter().collect(),
+            &inputs(),
+        );
+        // Row 2 has no matching k, so it is dropped from both levels and
+        // from the values.
+        assert_eq!(streams[&c0], vec![Crd(0), Stop(0), Done]);
+        assert_eq!(streams[&c1], vec![Crd(0), Crd(1), Stop(1), Done]);
+        assert_eq!(streams[&vals], vec![Val(4.0), Val(10.0), Stop(1), Done]);
+    }
+
+    #[test]
+    fn test_execute_matmul() {
+        let output = matmul(input("A").stage(), input("B").stage());
+        assert_eq!(run(output), vec![(vec![0, 0], 4.0), (vec![0, 1], 10.0)]);
+    }
+
+    #[test]
+    fn test_execute_shape_and_dense_levels() {
+        // Rows 1 and 2 and column 2 of A B are empty.
+        let entries = vec![(vec![0, 0], 4.0), (vec![0, 1], 10.0)];
+        for formats in [
+            [Compressed, Compressed],
+            [Dense, Compressed],
+            [Compressed, Dense],
+            [Dense, Dense],
+        ] {
+            let output = matmul(input("A").stage(), input("B").stage());
+            let scope = ScopeRef::default();
+            let found = stage_and_execute(output, &formats, vec![3, 3], &inputs(), &scope);
+            let expected =
+                SparseTensor::from_coo_with_formats(vec![3, 3], &formats, entries.clone());
+            assert_eq!(found, expected, "{formats:?}");
+        }
+    }
+
+    #[test]
+    fn test_execute_matadd() {
+        let output = matadd(input("A").stage(), input("B").stage());
+        let scope = ScopeRef::default();
+        let found = stage_and_execute(output, &[Dense, Compressed], vec![3, 3], &inputs(), &scope);
+        let expected = SparseTensor::from_coo_with_formats(
+            vec![3, 3],
+            &[Dense, Compressed],
+            vec![
+                (vec![0, 0], 5.0),
+                (vec![0, 2], 2.0),
+                (vec![1, 2], 5.0),
+                (vec![2, 1], 3.0),
+            ],
+        );
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_execute_nested() {
+        let (a, b) = (|| input("A").stage(), || input("B").stage());
+        assert_eq!(
+            run(matadd(matmul(a(), b()), a())),
+            vec![
+                (vec![0, 0], 5.0),
+                (vec![0, 1], 10.0),
+                (vec![0, 2], 2.0),
+                (vec![2, 1], 3.0)
+            ]
+        );
+        assert_eq!(
+            run(matmul(matadd(a(), b()), b())),
+            vec![(vec![0, 0], 20.0), (vec![0, 1], 10.0), (vec![1, 1], 25.0)]
+        );
+    }
+}
@@ -2,4 +2,103 @@ pub mod sym;
 pub mod sam;
 pub mod tensor;
 pub mod matmul;
-pub mod matadd;
\ No newline at end of file
+pub mod matadd;
+pub mod interpreter;
+pub mod simulator;
+pub mod storage;
+pub mod mtx;
+pub mod tns;
+pub mod samdir;
+pub mod einsum;
+pub mod lower;
+pub mod notation;
+pub mod elementwise;
+pub mod contract;
+pub mod schedule;
+pub mod tensor_expr;
+pub mod rewrite;
+pub mod egraph;
+pub mod pass;
+
+/// The matrices the tests of every module run on, and a harness executing
+/// the output `X` of a program on them.
+#[cfg(test)]
+mod test_util {
+    use fxhash::FxHashMap;
+
+    use crate::{
+        interpreter::execute,
+        sam::{LevelFormat, SamOps},
+        storage::SparseTensor,
+        sym::{Expr, ScopeRef},
+        tensor::{InputTensor, Tensor},
+    };
+
+    /// A = [[1, 0, 2], [0, 0, 0], [0, 3, 0]]
+    pub(crate) fn matrix_a() -> SparseTensor {
+        SparseTensor::from_coo(
+            vec![3, 3],
+            vec![(vec![0, 0], 1.0), (vec![0, 2], 2.0), (vec![2, 1], 3.0)],
+        )
+    }
+
+    /// B = [[4, 0, 0], [0, 0, 5], [0, 0, 0]]
+    pub(crate) fn matrix_b() -> SparseTensor {
+        SparseTensor::from_coo(vec![3, 3], vec![(vec![0, 0], 4.0), (vec![1, 2], 5.0)])
+    }
+
+    /// C = [[0, 0, 0], [0, 2, 0], [1, 0, 0]]
+    pub(crate) fn matrix_c() -> SparseTensor {
+        SparseTensor::from_coo(vec![3, 3], vec![(vec![1, 1], 2.0), (vec![2, 0], 1.0)])
+    }
+
+    /// Keys each of `items` by its name.
+    pub(crate) fn named<T>(
+        items: impl IntoIterator<Item = (&'static str, T)>,
+    ) -> FxHashMap<String, T> {
+        items
+            .into_iter()
+            .map(|(name, item)| (name.to_string(), item))
+            .collect()
+    }
+
+    /// A, B and C by name.
+    pub(crate) fn inputs() -> FxHashMap<String, SparseTensor> {
+        named([("A", matrix_a()), ("B", matrix_b()), ("C", matrix_c())])
+    }
+
+    /// A matrix input stored as DCSR, like A, B and C.
+    pub(crate) fn input(name: &str) -> InputTensor {
+        InputTensor {
+            name: name.to_string(),
+            formats: vec![LevelFormat::Compressed; 2],
+        }
+    }
+
+    /// Stages `output` into `scope` as `X`, stored as `formats`, and executes
+    /// it on `inputs`, giving `X` the shape `shape`.
+    pub(crate) fn stage_and_execute(
+        output: Tensor,
+        formats: &[LevelFormat],
+        shape: Vec<usize>,
+        inputs: &FxHashMap<String, SparseTensor>,
+        scope: &ScopeRef<SamOps>,
+    ) -> SparseTensor {
+        let root = SamOps::Root.stage(scope)[0];
+        let writers = output.stage_output("X", formats, root, scope);
+        let mut outputs = execute(
+            &scope.borrow(),
+            writers.into_iter().collect(),
+            inputs,
+            &named([("X", shape)]),
+        );
+        outputs.remove("X").unwrap()
+    }
+
+    /// The entries of the DCSR matrix `output` computes from A, B and C.
+    pub(crate) fn run(output: Tensor) -> Vec<(Vec<usize>, f64)> {
+        let scope = ScopeRef::default();
+        let formats = [LevelFormat::Compressed; 2];
+        stage_and_execute(output, &formats, vec![3, 3], &inputs(), &scope).to_coo()
+    }
+}
@@ -0,0 +1,855 @@
+use std::{fmt, ops::Range, rc::Rc};
+
+use fxhash::FxHashSet;
+
+use crate::{
+    sam::{JoinType, PrimitiveOp, SamOps},
+    schedule::{Schedule, Tiling},
+    sym::{Expr, ScopeRef, StageError, Sym},
+    tensor::{MetaFn, Tensor},
+};
+
+/// An index expression over operand tensors, each accessed with one index
+/// variable per level in storage order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IndexExpr {
+    Access { operand: usize, indices: Vec<char> },
+    Mul(Box<IndexExpr>, Box<IndexExpr>),
+    Add(Box<IndexExpr>, Box<IndexExpr>),
+}
+
+impl IndexExpr {
+    /// The number of accesses in this expression.
+    fn len(&self) -> usize {
+        match self {
+            IndexExpr::Access { .. } => 1,
+            IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => lhs.len() + rhs.len(),
+        }
+    }
+
+    /// The accesses in this expression, left to right.
+    fn accesses(&self) -> Vec<(usize, &[char])> {
+        match self {
+            IndexExpr::Access { operand, indices } => vec![(*operand, indices)],
+            IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => {
+                let mut accesses = lhs.accesses();
+                accesses.extend(rhs.accesses());
+                accesses
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LowerError {
+    Syntax(String),
+    /// An operand was accessed with a different number of indices than it has
+    /// levels.
+    Rank {
+        operand: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The same index was used twice in one access or in the output.
+    RepeatedIndex(char),
+    /// An output index that no operand is accessed with.
+    UnboundIndex(char),
+    /// No loop order visits every operand's levels in storage order with the
+    /// output indices outermost and in output order.
+    LoopOrder(String),
+    /// An index that only some terms of a sum are accessed with.
+    Broadcast(char),
+    /// A tensor name, or operand number, without a matching input.
+    UndefinedTensor(String),
+    /// A schedule transformation that does not apply to the loops it names.
+    Schedule(String),
+}
+
+impl fmt::Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LowerError::Syntax(message) => write!(f, "syntax error: {message}"),
+            LowerError::Rank {
+                operand,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{operand} has {expected} levels but is accessed with {found} indices"
+            ),
+            LowerError::RepeatedIndex(index) => write!(f, "index {index} is repeated"),
+            LowerError::UnboundIndex(index) => {
+                write!(f, "output index {index} does not index any operand")
+            }
+            LowerError::LoopOrder(message) => write!(f, "no valid loop order: {message}"),
+            LowerError::Broadcast(index) => {
+                write!(f, "index {index} must index every term of a sum")
+            }
+            LowerError::UndefinedTensor(name) => write!(f, "tensor {name} is not defined"),
+            LowerError::Schedule(message) => write!(f, "invalid schedule: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for LowerError {}
+
+struct Operand {
+    meta: Vec<MetaFn>,
+    comp: crate::tensor::CompFn,
+}
+
+/// A validated expression together with the loop order it is staged in.
+struct Plan {
+    expr: IndexExpr,
+    operands: Vec<Operand>,
+    /// The accesses of `expr` in order with the loops visiting their levels;
+    /// the ref streams of a staging are kept per access so one operand can be
+    /// accessed several times.
+    accesses: Vec<(usize, Vec<char>)>,
+    loop_order: Vec<char>,
+    /// The index each loop level is derived from and how it visits that
+    /// index's levels.
+    sources: Vec<(char, Tiling)>,
+    /// The number of lanes of every parallel loop level.
+    lanes: Vec<Option<usize>>,
+    /// The range of accesses of the subexpression that iterates each loop
+    /// level. Output indices are iterated by the whole expression, summed
+    /// indices by the smallest subexpression using them, which also sums them.
+    owners: Vec<Range<usize>>,
+    /// Whether each loop level may leave coordinates of the level above it
+    /// without any children, which then have to be dropped.
+    may_empty: Vec<bool>,
+    /// The loop level visiting the coordinates of each output index.
+    outputs: Vec<usize>,
+    /// Whether a sum over the whole expression encloses output levels, so
+    /// partial results have to be merged in a `Spacc`.
+    merges: bool,
+}
+
+/// The streams produced by one staging of a plan's loop nest.
+struct Staged {
+    /// The coordinates of every loop level.
+    crds: Vec<Sym>,
+    /// The innermost reference of every access.
+    refs: Vec<Sym>,
+}
+
+/// How far each access has been staged into the loop nest.
+struct Cursor {
+    /// The innermost reference of every access.
+    refs: Vec<Sym>,
+    /// The number of loops of every access that have been staged.
+    loops: Vec<usize>,
+    /// The reference every access looked up its current level with, from
+    /// which the tiles of a split level are looked up.
+    parents: Vec<Sym>,
+}
+
+/// The coordinate and per-access references a subexpression produces at one
+/// loop level, or `None` if none of its accesses use the level's index.
+type Level = Option<(Sym, Vec<(usize, Sym)>)>;
+
+impl Plan {
+    fn new(
+        expr: IndexExpr,
+        operands: Vec<Tensor>,
+        output: &[char],
+        schedule: &Schedule,
+    ) -> Result<Self, LowerError> {
+        let accesses: Vec<_> = expr
+            .accesses()
+            .into_iter()
+            .map(|(operand, indices)| (operand, indices.to_vec()))
+            .collect();
+        for (operand, indices) in &accesses {
+            let Some(tensor) = operands.get(*operand) else {
+                return Err(LowerError::UndefinedTensor(format!("operand {operand}")));
+            };
+            let expected = tensor.meta.len();
+            if indices.len() != expected {
+                return Err(LowerError::Rank {
+                    operand: format!("operand {operand}"),
+                    expected,
+                    found: indices.len(),
+                });
+            }
+            check_unique(indices)?;
+        }
+        check_unique(output)?;
+        let mut reductions = vec![];
+        for (_, indices) in &accesses {
+            for index in indices {
+                if !output.contains(index) && !reductions.contains(index) {
+                    reductions.push(*index);
+                }
+            }
+        }
+        if let Some(index) = output
+            .iter()
+            .find(|index| !accesses.iter().any(|(_, indices)| indices.contains(index)))
+        {
+            return Err(LowerError::UnboundIndex(*index));
+        }
+
+        let priority: Vec<char> = output.iter().chain(&reductions).copied().collect();
+        let loops = schedule.resolve(&priority)?;
+        let accesses: Vec<_> = accesses
+            .into_iter()
+            .map(|(operand, indices)| (operand, loops.expand(&indices)))
+            .collect();
+        let mut chains: Vec<_> = accesses.iter().map(|(_, vars)| vars.clone()).collect();
+        chains.extend(loops.order.iter().cloned());
+        let loop_order = loop_order(&loops.expand(&priority), &chains, &loops.fused)?;
+        let sources: Vec<_> = loop_order.iter().map(|var| loops.source(*var)).collect();
+        // A split output index is written from the coordinates of its tiles.
+        let outputs: Vec<usize> = output
+            .iter()
+            .map(|index| {
+                let vars = loops.expand(&[*index]);
+                let inner = vars[vars.len() - 1];
+                loop_order.iter().position(|var| *var == inner).unwrap()
+            })
+            .collect();
+        if outputs.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(LowerError::LoopOrder(format!(
+                "the operands' storage orders need loop order {}, which visits the output indices {} out of order",
+                loop_order.iter().collect::<String>(),
+                output.iter().collect::<String>()
+            )));
+        }
+        let merges = (0..loop_order.len())
+            .any(|level| !outputs.contains(&level) && outputs.iter().any(|&later| later > level));
+        let sums = sources
+            .iter()
+            .map(|(index, _)| match output.contains(index) {
+                true => Ok(0..accesses.len()),
+                false => owner(&expr, 0, *index).ok_or(LowerError::UnboundIndex(*index)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // A sum has to enclose the loops nested inside it, so it widens to
+        // the subexpression of any later loop spanning more terms.
+        let mut owners = sums.clone();
+        for level in (0..loop_order.len()).rev() {
+            for later in level + 1..loop_order.len() {
+                let (owner, other) = (&owners[level], &owners[later]);
+                if other.start <= owner.start && owner.end <= other.end {
+                    owners[level] = other.clone();
+                }
+            }
+        }
+        let mut empties = vec![];
+        for (level, (index, _)) in sources.iter().enumerate() {
+            let owner = &owners[level];
+            let empty = match iterate(subexpr(&expr, 0, owner), *index) {
+                // Widening the sum would add the terms without its index
+                // once per summed coordinate, so it is merged on its own.
+                Err(LowerError::Broadcast(_)) if *owner != sums[level] => {
+                    let (sum, operands) = hoist(&expr, operands, &sums[level], output, &sources)?;
+                    return Self::new(sum, operands, output, schedule);
+                }
+                empty => empty?.unwrap_or(false),
+            };
+            empties.push(empty && *owner == (0..accesses.len()));
+        }
+        let may_empty = (0..loop_order.len())
+            .map(|level| empties[level + 1..].contains(&true))
+            .collect();
+        Ok(Self {
+            expr,
+            operands: operands
+                .into_iter()
+                .map(|tensor| Operand {
+                    meta: tensor.meta,
+                    comp: tensor.comp,
+                })
+                .collect(),
+            accesses,
+            lanes: loop_order
+                .iter()
+                .map(|var| loops.lanes.get(var).copied())
+                .collect(),
+            loop_order,
+            sources,
+            owners,
+            may_empty,
+            outputs,
+            merges,
+        })
+    }
+
+    fn stage(&self, refstream: Sym, scope: &ScopeRef<SamOps>) -> Result<Staged, StageError> {
+        let [root] = SamOps::Root.try_stage_n(scope)?;
+        let [t0] = SamOps::Repeat {
+            target: root,
+            repeat: refstream,
+        }
+        .try_stage_n(scope)?;
+        let mut cursor = Cursor {
+            refs: vec![t0; self.accesses.len()],
+            loops: vec![0; self.accesses.len()],
+            parents: vec![t0; self.accesses.len()],
+        };
+        let mut crds = vec![];
+        for (level, var) in self.loop_order.iter().enumerate() {
+            let Some((crd, updated)) =
+                self.stage_level(&self.expr, 0, level, &self.owners[level], &cursor, scope)?
+            else {
+                return Err(StageError::Type {
+                    op: format!("loop {var}"),
+                    reason: "no operand is accessed with its index".to_string(),
+                });
+            };
+            for (access, mut reference) in updated {
+                if self.accesses[access].1.get(cursor.loops[access]) == Some(var) {
+                    cursor.loops[access] += 1;
+                    if let (_, Tiling::Outer(_)) = self.sources[level] {
+                        cursor.parents[access] = cursor.refs[access];
+                    }
+                }
+                // Everything nested in a parallel loop derives from its refs.
+                if let Some(lanes) = self.lanes[level] {
+                    [reference] = SamOps::Parallel { reference, lanes }.try_stage_n(scope)?;
+                }
+                cursor.refs[access] = reference;
+            }
+            crds.push(crd);
+        }
+        Ok(Staged {
+            crds,
+            refs: cursor.refs,
+        })
+    }
+
+    /// Stages loop `level` for `expr`, whose first access is numbered `first`,
+    /// when the subexpression with accesses `owner` iterates it.
+    fn stage_level(
+        &self,
+        expr: &IndexExpr,
+        first: usize,
+        level: usize,
+        owner: &Range<usize>,
+        cursor: &Cursor,
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<Level, StageError> {
+        let end = first + expr.len();
+        if end <= owner.start || owner.end <= first {
+            return Ok(None);
+        }
+        match expr {
+            IndexExpr::Access { operand, .. } => {
+                let vars = &self.accesses[first].1;
+                let position = cursor.loops[first];
+                if vars.get(position) != Some(&self.loop_order[level]) {
+                    return Ok(None);
+                }
+                // The tiles of a split level are staged as one more level.
+                let storage = vars[..position]
+                    .iter()
+                    .filter(|var| !matches!(self.source(**var).1, Tiling::Outer(_)))
+                    .count();
+                let meta = &self.operands[*operand].meta[storage];
+                let [reference, crd] = match self.sources[level].1 {
+                    Tiling::Whole => meta(cursor.refs[first], scope)?.into(),
+                    Tiling::Outer(factor) => {
+                        let (_, crd) = meta(cursor.refs[first], scope)?;
+                        SamOps::Split { crd, factor }.try_stage_n(scope)?
+                    }
+                    Tiling::Inner(factor) => {
+                        let (level_ref, level_crd) = meta(cursor.parents[first], scope)?;
+                        SamOps::Tilelookup {
+                            reference: cursor.refs[first],
+                            level_ref,
+                            level_crd,
+                            factor,
+                        }
+                        .try_stage_n(scope)?
+                    }
+                };
+                Ok(Some((crd, vec![(first, reference)])))
+            }
+            IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => {
+                let split = first + lhs.len();
+                let lhs = self.stage_level(lhs, first, level, owner, cursor, scope)?;
+                let rhs = self.stage_level(rhs, split, level, owner, cursor, scope)?;
+                let tp = match expr {
+                    IndexExpr::Add(..) => JoinType::Union,
+                    _ => JoinType::Intersect,
+                };
+                // Outside the owner, the other side does not loop over the
+                // index at all.
+                let inside = owner.start <= first && end <= owner.end;
+                Ok(match (lhs, rhs) {
+                    (Some(lhs), Some(rhs)) => Some(join(lhs, rhs, tp, scope)?),
+                    (Some((crd, mut present)), None) => {
+                        if inside {
+                            present.extend(repeat(split..end, &cursor.refs, present[0].1, scope)?);
+                        }
+                        Some((crd, present))
+                    }
+                    (None, Some((crd, mut present))) => {
+                        if inside {
+                            present.extend(repeat(
+                                first..split,
+                                &cursor.refs,
+                                present[0].1,
+                                scope,
+                            )?);
+                        }
+                        Some((crd, present))
+                    }
+                    (None, None) => None,
+                })
+            }
+        }
+    }
+
+    /// The index loop `var` is derived from and how it visits its levels.
+    fn source(&self, var: char) -> (char, Tiling) {
+        let level = self.loop_order.iter().position(|other| *other == var);
+        self.sources[level.expect("Unknown loop")]
+    }
+
+    fn stage_values(
+        &self,
+        expr: &IndexExpr,
+        first: usize,
+        refs: &[Sym],
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<Sym, StageError> {
+        let mut values = match expr {
+            IndexExpr::Access { operand, .. } => {
+                (self.operands[*operand].comp)(refs[first], scope)?
+            }
+            IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => {
+                let split = first + lhs.len();
+                let lhs = self.stage_values(lhs, first, refs, scope)?;
+                let rhs = self.stage_values(rhs, split, refs, scope)?;
+                let op = match expr {
+                    IndexExpr::Add(..) => PrimitiveOp::Add,
+                    _ => PrimitiveOp::Mul,
+                };
+                SamOps::ALU {
+                    op,
+                    inputs: vec![lhs, rhs],
+                }
+                .try_stage(scope)?[0]
+            }
+        };
+        // Sums over the whole expression are staged by `stage_outputs`.
+        let range = first..first + expr.len();
+        if range != (0..self.accesses.len()) {
+            for owner in &self.owners {
+                if *owner == range {
+                    values = SamOps::Reduce {
+                        inputs: values,
+                        op: PrimitiveOp::Add,
+                        keep_empty: true,
+                    }
+                    .try_stage(scope)?[0];
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// The coordinates of every output level, before dropping any, and the
+    /// values, with the sums over the whole expression applied.
+    fn stage_outputs(
+        &self,
+        refstream: Sym,
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<(Vec<Sym>, Sym), StageError> {
+        let Staged { crds, refs } = self.stage(refstream, scope)?;
+        let mut values = self.stage_values(&self.expr, 0, &refs, scope)?;
+        let root = 0..self.accesses.len();
+        let mut kept = vec![];
+        for level in (0..self.loop_order.len()).rev() {
+            if self.outputs.contains(&level) {
+                // Empty sums produce no value, so their coordinates have to go
+                // for the values to stay aligned with them.
+                let crd = match kept.is_empty() {
+                    true => self.dropped(&crds[level..], &self.may_empty[level..], scope)?[0],
+                    false => crds[level],
+                };
+                kept.insert(0, crd);
+            } else if self.owners[level] != root {
+                continue;
+            } else if kept.is_empty() {
+                values = SamOps::Reduce {
+                    inputs: values,
+                    op: PrimitiveOp::Add,
+                    keep_empty: false,
+                }
+                .try_stage(scope)?[0];
+            } else {
+                let merged = SamOps::Spacc { crds: kept, values }.try_stage(scope)?;
+                values = merged[merged.len() - 1];
+                kept = merged[..merged.len() - 1].to_vec();
+            }
+        }
+        Ok((kept, values))
+    }
+
+    /// The coordinates `crds` of nested levels, each with the coordinates whose
+    /// subtrees came up empty dropped, outermost first.
+    fn dropped(
+        &self,
+        crds: &[Sym],
+        may_empty: &[bool],
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<Vec<Sym>, StageError> {
+        let mut dropped = crds.to_vec();
+        for level in (0..crds.len() - 1).rev() {
+            if may_empty[level] {
+                dropped[level] = SamOps::CoordDrop {
+                    inner: dropped[level + 1],
+                    outer: crds[level],
+                    values: None,
+                }
+                .try_stage(scope)?[0];
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Whether the coordinates of each output level may have subtrees that
+    /// come up empty. Without merging, the output levels lead the loop levels.
+    fn output_may_empty(&self) -> Vec<bool> {
+        match self.merges {
+            true => self
+                .outputs
+                .iter()
+                .map(|&level| self.may_empty[level])
+                .collect(),
+            false => self.may_empty.clone(),
+        }
+    }
+
+    /// The coordinates of output `level`, with the coordinates whose subtrees
+    /// came up empty dropped at every level and the fibers under them too, and
+    /// with `values`, the values of the innermost level with the same fibers
+    /// dropped.
+    fn stage_kept(
+        &self,
+        level: usize,
+        values: Option<Sym>,
+        refstream: Sym,
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<(Sym, Option<Sym>), StageError> {
+        let crds = match self.merges {
+            true => self.stage_outputs(refstream, scope)?.0,
+            false => self.stage(refstream, scope)?.crds,
+        };
+        let may_empty = self.output_may_empty();
+        let dropped = self.dropped(&crds, &may_empty, scope)?;
+        if level == 0 || !may_empty[..level].contains(&true) {
+            return Ok((dropped[level], values));
+        }
+        let kept = SamOps::CoordDrop {
+            inner: dropped[level],
+            outer: crds[level - 1],
+            values,
+        }
+        .try_stage(scope)?;
+        Ok((kept[1], kept.get(2).copied()))
+    }
+
+    fn stage_meta(
+        &self,
+        level: usize,
+        refstream: Sym,
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<(Sym, Sym), StageError> {
+        let (coords, _) = self.stage_kept(level, None, refstream, scope)?;
+        Ok((SamOps::Genref { coords }.try_stage(scope)?[0], coords))
+    }
+
+    fn stage_comp(&self, refstream: Sym, scope: &ScopeRef<SamOps>) -> Result<Sym, StageError> {
+        let values = self.stage_outputs(refstream, scope)?.1;
+        // The values of the innermost level only lose fibers if a level above
+        // may drop coordinates.
+        let last = self.outputs.len().saturating_sub(1);
+        if !self.output_may_empty()[..last].contains(&true) {
+            return Ok(values);
+        }
+        let (_, values) = self.stage_kept(last, Some(values), refstream, scope)?;
+        Ok(values.expect("Kept values were requested"))
+    }
+}
+
+fn check_unique(indices: &[char]) -> Result<(), LowerError> {
+    let mut seen = FxHashSet::default();
+    match indices.iter().find(|index| !seen.insert(**index)) {
+        Some(index) => Err(LowerError::RepeatedIndex(*index)),
+        None => Ok(()),
+    }
+}
+
+/// Orders the loops so they visit every chain in order, with the second loop
+/// of each `fused` pair directly inside the first, preferring loops earlier in
+/// `priority`. The chains are the operands' storage orders and the schedule's
+/// reorderings.
+fn loop_order(
+    priority: &[char],
+    chains: &[Vec<char>],
+    fused: &[(char, char)],
+) -> Result<Vec<char>, LowerError> {
+    let ready = |order: &[char], var: char| {
+        !order.contains(&var)
+            && chains
+                .iter()
+                .all(|chain| match chain.iter().position(|other| *other == var) {
+                    Some(at) => chain[..at].iter().all(|other| order.contains(other)),
+                    None => true,
+                })
+    };
+    let mut order = vec![];
+    while order.len() < priority.len() {
+        let next = priority.iter().find_map(|var| {
+            if !ready(&order, *var) || fused.iter().any(|(_, inner)| inner == var) {
+                return None;
+            }
+            match fused.iter().find(|(outer, _)| outer == var) {
+                Some((_, inner)) => {
+                    let mut order = order.clone();
+                    order.push(*var);
+                    ready(&order, *inner).then_some(vec![*var, *inner])
+                }
+                None => Some(vec![*var]),
+            }
+        });
+        match next {
+            Some(vars) => order.extend(vars),
+            None => {
+                return Err(LowerError::LoopOrder(
+                    "the operands' storage orders and the schedule conflict".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// The accesses of the smallest subexpression of `expr` that contains every
+/// access using `index`, where `expr` starts at access `first`.
+fn owner(expr: &IndexExpr, first: usize, index: char) -> Option<Range<usize>> {
+    match expr {
+        IndexExpr::Access { indices, .. } => indices.contains(&index).then_some(first..first + 1),
+        IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => {
+            let split = first + lhs.len();
+            match (owner(lhs, first, index), owner(rhs, split, index)) {
+                (Some(_), Some(_)) => Some(first..split + rhs.len()),
+                (lhs, rhs) => lhs.or(rhs),
+            }
+        }
+    }
+}
+
+/// The subexpression of `expr`, which starts at access `first`, with the
+/// accesses `range`.
+fn subexpr<'a>(expr: &'a IndexExpr, first: usize, range: &Range<usize>) -> &'a IndexExpr {
+    match expr {
+        IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs)
+            if range != &(first..first + expr.len()) =>
+        {
+            let split = first + lhs.len();
+            match range.start < split {
+                true => subexpr(lhs, first, range),
+                false => subexpr(rhs, split, range),
+            }
+        }
+        _ => expr,
+    }
+}
+
+/// Replaces the subexpression of `expr` with the accesses `range` by an access
+/// to a new operand, which lowers that subexpression with the sums it owns
+/// merged. The new operand keeps the `output` indices and the ones summed
+/// outside the subexpression, in the order `sources` visits them.
+fn hoist(
+    expr: &IndexExpr,
+    mut operands: Vec<Tensor>,
+    range: &Range<usize>,
+    output: &[char],
+    sources: &[(char, Tiling)],
+) -> Result<(IndexExpr, Vec<Tensor>), LowerError> {
+    let sum = subexpr(expr, 0, range);
+    let mut kept = vec![];
+    for (index, _) in sources {
+        let inside = sum
+            .accesses()
+            .iter()
+            .any(|(_, indices)| indices.contains(index));
+        let summed = !output.contains(index)
+            && owner(expr, 0, *index)
+                .is_some_and(|owner| range.start <= owner.start && owner.end <= range.end);
+        if inside && !summed && !kept.contains(index) {
+            kept.push(*index);
+        }
+    }
+    let tensor = lower(sum.clone(), operands.clone(), &kept)?;
+    let access = IndexExpr::Access {
+        operand: operands.len(),
+        indices: kept,
+    };
+    operands.push(tensor);
+    Ok((replace(expr, 0, range, access), operands))
+}
+
+/// `expr`, which starts at access `first`, with the subexpression with the
+/// accesses `range` replaced by `by`.
+fn replace(expr: &IndexExpr, first: usize, range: &Range<usize>, by: IndexExpr) -> IndexExpr {
+    if *range == (first..first + expr.len()) {
+        return by;
+    }
+    match expr {
+        IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => {
+            let split = first + lhs.len();
+            let (lhs, rhs) = match range.start < split {
+                true => (replace(lhs, first, range, by), (**rhs).clone()),
+                false => ((**lhs).clone(), replace(rhs, split, range, by)),
+            };
+            match expr {
+                IndexExpr::Add(..) => IndexExpr::Add(Box::new(lhs), Box::new(rhs)),
+                _ => IndexExpr::Mul(Box::new(lhs), Box::new(rhs)),
+            }
+        }
+        IndexExpr::Access { .. } => expr.clone(),
+    }
+}
+
+/// Whether `expr` loops over `index`, and if so whether that loop may come up
+/// empty where its operands' fibers do not.
+fn iterate(expr: &IndexExpr, index: char) -> Result<Option<bool>, LowerError> {
+    match expr {
+        IndexExpr::Access { indices, .. } => Ok(indices.contains(&index).then_some(false)),
+        IndexExpr::Mul(lhs, rhs) | IndexExpr::Add(lhs, rhs) => {
+            match (expr, iterate(lhs, index)?, iterate(rhs, index)?) {
+                (IndexExpr::Mul(..), Some(_), Some(_)) => Ok(Some(true)),
+                (IndexExpr::Add(..), Some(lhs), Some(rhs)) => Ok(Some(lhs && rhs)),
+                (IndexExpr::Add(..), Some(_), None) | (IndexExpr::Add(..), None, Some(_)) => {
+                    Err(LowerError::Broadcast(index))
+                }
+                (_, lhs, rhs) => Ok(lhs.or(rhs)),
+            }
+        }
+    }
+}
+
+/// Joins the coordinates of two subexpressions, carrying every reference of
+/// both sides through the join.
+fn join(
+    (lhs_crd, lhs): (Sym, Vec<(usize, Sym)>),
+    (rhs_crd, rhs): (Sym, Vec<(usize, Sym)>),
+    tp: JoinType,
+    scope: &ScopeRef<SamOps>,
+) -> Result<(Sym, Vec<(usize, Sym)>), StageError> {
+    let join = |ref1, ref2| {
+        SamOps::Join {
+            ref1,
+            ref2,
+            crd1: lhs_crd,
+            crd2: rhs_crd,
+            tp,
+        }
+        .try_stage_n::<3>(scope)
+    };
+    let crd = join(lhs[0].1, rhs[0].1)?[2];
+    let mut refs = vec![];
+    for (access, reference) in &lhs {
+        refs.push((*access, join(*reference, rhs[0].1)?[0]));
+    }
+    for (access, reference) in &rhs {
+        refs.push((*access, join(lhs[0].1, *reference)?[1]));
+    }
+    Ok((crd, refs))
+}
+
+/// Repeats the references of the accesses in `range` over `repeat`.
+fn repeat(
+    range: std::ops::Range<usize>,
+    refs: &[Sym],
+    repeat: Sym,
+    scope: &ScopeRef<SamOps>,
+) -> Result<Vec<(usize, Sym)>, StageError> {
+    range
+        .map(|access| {
+            let target = refs[access];
+            Ok((
+                access,
+                SamOps::Repeat { target, repeat }.try_stage(scope)?[0],
+            ))
+        })
+        .collect()
+}
+
+/// Lowers `expr` over `operands` into a tensor with one level per `output`
+/// index. Every other index is summed over the smallest subexpression using
+/// it, e.g. `sum_k(A(i,k) * B(j,k)) + C(i,j)`.
+///
+/// Loops run over the output indices in order, with the summed ones as far
+/// inside as the operands' storage orders allow; sums that enclose output
+/// levels merge their partial results in a `Spacc`. A sum of part of the
+/// terms of an addition that encloses output levels, as in
+/// `sum_k(A(i,k) * B(k,j)) + C(i,j)`, is lowered and merged on its own first.
+/// Like `matmul`, the result is `Tensor::computed`, so it can be an operand of
+/// other combinators.
+pub fn lower(
+    expr: IndexExpr,
+    operands: Vec<Tensor>,
+    output: &[char],
+) -> Result<Tensor, LowerError> {
+    lower_with(expr, operands, output, &Schedule::default())
+}
+
+/// Lowers `expr` like `lower`, with the loop nest transformed by `schedule`.
+/// The outer loop of a split output index is merged away like a sum, so the
+/// result has the same levels whatever the schedule.
+pub fn lower_with(
+    expr: IndexExpr,
+    operands: Vec<Tensor>,
+    output: &[char],
+    schedule: &Schedule,
+) -> Result<Tensor, LowerError> {
+    let plan = Rc::new(Plan::new(expr, operands, output, schedule)?);
+    let meta = (0..output.len())
+        .map(|level| {
+            let plan = plan.clone();
+            Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
+                plan.stage_meta(level, refstream, scope)
+            }) as MetaFn
+        })
+        .collect();
+    Ok(Tensor {
+        meta,
+        comp: Rc::new(move |refstream, scope: &ScopeRef<SamOps>| plan.stage_comp(refstream, scope)),
+    }
+    .computed())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{sam::LevelFormat::Compressed, tensor::InputTensor};
+
+    use super::{lower, IndexExpr, LowerError};
+
+    #[test]
+    fn test_undefined_operand() {
+        let a = InputTensor {
+            name: "A".to_string(),
+            formats: vec![Compressed; 2],
+        }
+        .stage();
+        let access = |operand, indices: &str| {
+            Box::new(IndexExpr::Access {
+                operand,
+                indices: indices.chars().collect(),
+            })
+        };
+        let expr = IndexExpr::Mul(access(0, "ij"), access(1, "j"));
+        assert_eq!(
+            lower(expr, vec![a], &['i']).err(),
+            Some(LowerError::UndefinedTensor("operand 1".to_string()))
+        );
+    }
+}
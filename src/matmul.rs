@@ -1,31 +1,84 @@
 use std::rc::Rc;
 
-use crate::{sam::SamOps, sym::Expr, tensor::Tensor};
+use crate::{
+    sam::{JoinType, PrimitiveOp, SamOps},
+    sym::{Expr, ScopeRef, StageError, Sym},
+    tensor::{level, MetaFn, Tensor},
+};
 
+/// The loop order of a matrix multiplication, which decides how its operands
+/// have to be stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dataflow {
+    /// i,j,k: dot products of the rows of A (i,k) with the columns of B
+    /// stored column-major (j,k).
+    InnerProduct,
+    /// k,i,j: merges the outer products of the columns of A stored
+    /// column-major (k,i) with the rows of B (k,j).
+    OuterProduct,
+    /// i,k,j: merges the rows of B (k,j) scaled by each row of A (i,k).
+    Gustavson,
+}
+
+/// Multiplies `a` and `b` with the inner-product dataflow.
 pub fn matmul(a: Tensor, b: Tensor) -> Tensor {
+    inner_product(a, b)
+}
+
+/// Multiplies `a` and `b`, stored as `dataflow` expects, with that dataflow.
+pub fn matmul_with(dataflow: Dataflow, a: Tensor, b: Tensor) -> Tensor {
+    match dataflow {
+        Dataflow::InnerProduct => inner_product(a, b),
+        Dataflow::OuterProduct => outer_product(a, b),
+        Dataflow::Gustavson => gustavson(a, b),
+    }
+}
+
+/// Multiplies `a` (i,k) by `b` stored column-major (j,k), like
+/// `einsum("ik,jk->ij")`, taking the dot product of every row of A with every
+/// column of B. The result is `Tensor::computed`, so it can be an operand of
+/// other combinators.
+pub fn inner_product(a: Tensor, b: Tensor) -> Tensor {
+    dot_products(a, b).computed()
+}
+
+/// Multiplies `a` stored column-major (k,i) by `b` (k,j), like
+/// `einsum("ki,kj->ij")`, merging the outer product of every column of A
+/// with the matching row of B in a `Spacc`.
+pub fn outer_product(a: Tensor, b: Tensor) -> Tensor {
+    merged(move |refstream, scope| stage_outer_product(&a, &b, refstream, scope)).computed()
+}
+
+/// Multiplies `a` (i,k) by `b` (k,j), like `einsum("ik,kj->ij")`, merging
+/// the rows of B scaled by each row of A in a `Spacc`.
+pub fn gustavson(a: Tensor, b: Tensor) -> Tensor {
+    merged(move |refstream, scope| stage_gustavson(&a, &b, refstream, scope)).computed()
+}
+
+fn dot_products(a: Tensor, b: Tensor) -> Tensor {
     let a_meta = a.meta.clone();
     let b_meta = b.meta.clone();
-    let meta0 = move |refstream, scope: &_| {
-        let root = SamOps::Root.stage(scope)[0];
+    let meta0 = move |refstream, scope: &_| -> Result<_, StageError> {
+        let root = SamOps::Root.try_stage(scope)?[0];
         let t0 = SamOps::Repeat {
             target: root,
             repeat: refstream,
         }
-        .stage(scope)[0];
-        let (r0, c0) = a_meta[0](t0, scope);
+        .try_stage(scope)?[0];
+        let (r0, c0) = level(&a_meta, 0)?(t0, scope)?;
         let r1 = SamOps::Repeat {
             target: root,
             repeat: r0,
         }
-        .stage(scope)[0];
-        let (r2, c2) = b_meta[0](r1, scope);
+        .try_stage(scope)?[0];
+        let (r2, c2) = level(&b_meta, 0)?(r1, scope)?;
         let r3 = SamOps::Repeat {
             target: r0,
             repeat: r2,
         }
-        .stage(scope)[0];
-        let (r4, c4) = a_meta[1](r3, scope);
-        let (r5, c5) = b_meta[1](r2, scope);
+        .try_stage(scope)?[0];
+        let (r4, c4) = level(&a_meta, 1)?(r3, scope)?;
+        let (r5, c5) = level(&b_meta, 1)?(r2, scope)?;
         let icrd = SamOps::Join {
             ref1: r4,
             ref2: r5,
@@ -33,43 +86,45 @@ pub fn matmul(a: Tensor, b: Tensor) -> Tensor {
             crd2: c5,
             tp: crate::sam::JoinType::Intersect,
         }
-        .stage(scope)[2];
+        .try_stage(scope)?[2];
         let jk = SamOps::CoordDrop {
             inner: icrd,
             outer: c2,
+            values: None,
         }
-        .stage(scope)[0];
+        .try_stage(scope)?[0];
         let ijk = SamOps::CoordDrop {
             inner: jk,
             outer: c0,
+            values: None,
         }
-        .stage(scope)[0];
-        (SamOps::Genref { coords: ijk }.stage(scope)[0], ijk)
+        .try_stage(scope)?[0];
+        Ok((SamOps::Genref { coords: ijk }.try_stage(scope)?[0], ijk))
     };
 
     let a_meta = a.meta.clone();
     let b_meta = b.meta.clone();
-    let meta1 = move |refstream, scope: &_| {
-        let root = SamOps::Root.stage(scope)[0];
+    let meta1 = move |refstream, scope: &_| -> Result<_, StageError> {
+        let root = SamOps::Root.try_stage(scope)?[0];
         let t0 = SamOps::Repeat {
             target: root,
             repeat: refstream,
         }
-        .stage(scope)[0];
-        let (r0, _c0) = a_meta[0](t0, scope);
+        .try_stage(scope)?[0];
+        let (r0, c0) = level(&a_meta, 0)?(t0, scope)?;
         let r1 = SamOps::Repeat {
             target: root,
             repeat: r0,
         }
-        .stage(scope)[0];
-        let (r2, c2) = b_meta[0](r1, scope);
+        .try_stage(scope)?[0];
+        let (r2, c2) = level(&b_meta, 0)?(r1, scope)?;
         let r3 = SamOps::Repeat {
             target: r0,
             repeat: r2,
         }
-        .stage(scope)[0];
-        let (r4, c4) = a_meta[1](r3, scope);
-        let (r5, c5) = b_meta[1](r2, scope);
+        .try_stage(scope)?[0];
+        let (r4, c4) = level(&a_meta, 1)?(r3, scope)?;
+        let (r5, c5) = level(&b_meta, 1)?(r2, scope)?;
         let icrd = SamOps::Join {
             ref1: r4,
             ref2: r5,
@@ -77,58 +132,78 @@ pub fn matmul(a: Tensor, b: Tensor) -> Tensor {
             crd2: c5,
             tp: crate::sam::JoinType::Intersect,
         }
-        .stage(scope)[2];
+        .try_stage(scope)?[2];
         let jk = SamOps::CoordDrop {
             inner: icrd,
             outer: c2,
+            values: None,
+        }
+        .try_stage(scope)?[0];
+        // Rows left without any j are dropped from level 0, so drop their fibers too.
+        let jk = SamOps::CoordDrop {
+            inner: jk,
+            outer: c0,
+            values: None,
         }
-        .stage(scope)[0];
-        (SamOps::Genref { coords: jk }.stage(scope)[0], jk)
+        .try_stage(scope)?[1];
+        Ok((SamOps::Genref { coords: jk }.try_stage(scope)?[0], jk))
     };
 
-    let comp = move |refstream, scope: &_| {
-        let root = SamOps::Root.stage(scope)[0];
+    let comp = move |refstream, scope: &_| -> Result<_, StageError> {
+        let root = SamOps::Root.try_stage(scope)?[0];
         let t0 = SamOps::Repeat {
             target: root,
             repeat: refstream,
         }
-        .stage(scope)[0];
-        let (r0, _c0) = a.meta[0](t0, scope);
+        .try_stage(scope)?[0];
+        let (r0, c0) = level(&a.meta, 0)?(t0, scope)?;
         let r1 = SamOps::Repeat {
             target: root,
             repeat: r0,
         }
-        .stage(scope)[0];
-        let (r2, _c2) = b.meta[0](r1, scope);
+        .try_stage(scope)?[0];
+        let (r2, c2) = level(&b.meta, 0)?(r1, scope)?;
         let r3 = SamOps::Repeat {
             target: r0,
             repeat: r2,
         }
-        .stage(scope)[0];
-        let (r4, c4) = a.meta[1](r3, scope);
-        let (r5, c5) = b.meta[1](r2, scope);
-        let [ika, ikb, _icrd] = SamOps::Join {
+        .try_stage(scope)?[0];
+        let (r4, c4) = level(&a.meta, 1)?(r3, scope)?;
+        let (r5, c5) = level(&b.meta, 1)?(r2, scope)?;
+        let [ika, ikb, icrd] = SamOps::Join {
             ref1: r4,
             ref2: r5,
             crd1: c4,
             crd2: c5,
             tp: crate::sam::JoinType::Intersect,
         }
-        .stage(scope)[..] else {
-            panic!()
-        };
-        let v_a = (a.comp)(ika, scope);
-        let v_b = (b.comp)(ikb, scope);
+        .try_stage_n(scope)?;
+        let v_a = (a.comp)(ika, scope)?;
+        let v_b = (b.comp)(ikb, scope)?;
         let mul = SamOps::ALU {
             op: crate::sam::PrimitiveOp::Mul,
             inputs: vec![v_a, v_b],
         }
-        .stage(scope)[0];
-        SamOps::Reduce {
+        .try_stage(scope)?[0];
+        let sums = SamOps::Reduce {
             inputs: mul,
             op: crate::sam::PrimitiveOp::Add,
+            keep_empty: false,
+        }
+        .try_stage(scope)?[0];
+        let jk = SamOps::CoordDrop {
+            inner: icrd,
+            outer: c2,
+            values: None,
         }
-        .stage(scope)[0]
+        .try_stage(scope)?[0];
+        // The sums of the rows dropped from level 0 are dropped along with them.
+        Ok(SamOps::CoordDrop {
+            inner: jk,
+            outer: c0,
+            values: Some(sums),
+        }
+        .try_stage(scope)?[2])
     };
     Tensor {
         meta: vec![Rc::new(meta0), Rc::new(meta1)],
@@ -136,17 +211,148 @@ pub fn matmul(a: Tensor, b: Tensor) -> Tensor {
     }
 }
 
+/// The tensor whose levels and values are the streams `stage` outputs: the
+/// coordinates of both levels and the values, merged in a `Spacc`.
+fn merged(
+    stage: impl Fn(Sym, &ScopeRef<SamOps>) -> Result<[Sym; 3], StageError> + 'static,
+) -> Tensor {
+    let stage = Rc::new(stage);
+    let output = move |port: usize| {
+        let stage = stage.clone();
+        move |refstream, scope: &ScopeRef<SamOps>| Ok(stage(refstream, scope)?[port])
+    };
+    let meta = (0..2)
+        .map(|level| {
+            let coords = output(level);
+            Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
+                let coords = coords(refstream, scope)?;
+                Ok((SamOps::Genref { coords }.try_stage(scope)?[0], coords))
+            }) as MetaFn
+        })
+        .collect();
+    Tensor {
+        meta,
+        comp: Rc::new(output(2)),
+    }
+}
+
+fn multiply(a: Sym, b: Sym, scope: &ScopeRef<SamOps>) -> Result<Sym, StageError> {
+    Ok(SamOps::ALU {
+        op: PrimitiveOp::Mul,
+        inputs: vec![a, b],
+    }
+    .try_stage(scope)?[0])
+}
+
+fn stage_outer_product(
+    a: &Tensor,
+    b: &Tensor,
+    refstream: Sym,
+    scope: &ScopeRef<SamOps>,
+) -> Result<[Sym; 3], StageError> {
+    let root = SamOps::Root.try_stage(scope)?[0];
+    let t0 = SamOps::Repeat {
+        target: root,
+        repeat: refstream,
+    }
+    .try_stage(scope)?[0];
+    let (r0, c0) = level(&a.meta, 0)?(t0, scope)?;
+    let (r1, c1) = level(&b.meta, 0)?(t0, scope)?;
+    let [ka, kb, _kcrd] = SamOps::Join {
+        ref1: r0,
+        ref2: r1,
+        crd1: c0,
+        crd2: c1,
+        tp: JoinType::Intersect,
+    }
+    .try_stage_n(scope)?;
+    let (ia, icrd) = level(&a.meta, 1)?(ka, scope)?;
+    let ib = SamOps::Repeat {
+        target: kb,
+        repeat: ia,
+    }
+    .try_stage(scope)?[0];
+    let (jb, jcrd) = level(&b.meta, 1)?(ib, scope)?;
+    let ja = SamOps::Repeat {
+        target: ia,
+        repeat: jb,
+    }
+    .try_stage(scope)?[0];
+    let products = multiply((a.comp)(ja, scope)?, (b.comp)(jb, scope)?, scope)?;
+    let [i, j, vals] = SamOps::Spacc {
+        crds: vec![icrd, jcrd],
+        values: products,
+    }
+    .try_stage_n(scope)?;
+    Ok([i, j, vals])
+}
+
+fn stage_gustavson(
+    a: &Tensor,
+    b: &Tensor,
+    refstream: Sym,
+    scope: &ScopeRef<SamOps>,
+) -> Result<[Sym; 3], StageError> {
+    let root = SamOps::Root.try_stage(scope)?[0];
+    let t0 = SamOps::Repeat {
+        target: root,
+        repeat: refstream,
+    }
+    .try_stage(scope)?[0];
+    let (r0, icrd) = level(&a.meta, 0)?(t0, scope)?;
+    let r1 = SamOps::Repeat {
+        target: root,
+        repeat: r0,
+    }
+    .try_stage(scope)?[0];
+    let (r2, c2) = level(&a.meta, 1)?(r0, scope)?;
+    let (r3, c3) = level(&b.meta, 0)?(r1, scope)?;
+    let [ka, kb, _kcrd] = SamOps::Join {
+        ref1: r2,
+        ref2: r3,
+        crd1: c2,
+        crd2: c3,
+        tp: JoinType::Intersect,
+    }
+    .try_stage_n(scope)?;
+    let (jb, jcrd) = level(&b.meta, 1)?(kb, scope)?;
+    let ja = SamOps::Repeat {
+        target: ka,
+        repeat: jb,
+    }
+    .try_stage(scope)?[0];
+    let products = multiply((a.comp)(ja, scope)?, (b.comp)(jb, scope)?, scope)?;
+    let [j, vals] = SamOps::Spacc {
+        crds: vec![jcrd],
+        values: products,
+    }
+    .try_stage_n(scope)?;
+    // Rows whose partial products all missed B are left empty, so drop them
+    // along with their values.
+    let [i, j, vals] = SamOps::CoordDrop {
+        inner: j,
+        outer: icrd,
+        values: Some(vals),
+    }
+    .try_stage_n(scope)?;
+    Ok([i, j, vals])
+}
+
 #[cfg(test)]
 mod test {
+    use std::{cell::Cell, rc::Rc};
 
     use graphviz_rust::printer::{DotPrinter, PrinterContext};
 
     use crate::{
+        sam::LevelFormat::{self, Compressed},
+        storage::SparseTensor,
         sym::{Expr, ScopeRef},
-        tensor::InputTensor,
+        tensor::{InputTensor, MetaFn, Tensor},
+        test_util::{input, named, stage_and_execute},
     };
 
-    use super::{matmul, SamOps};
+    use super::{dot_products, matmul, matmul_with, Dataflow, SamOps};
 
     #[test]
     fn test_matmul() {
@@ -154,14 +360,14 @@ mod test {
         let root = SamOps::Root.stage(&scope)[0];
         let tensor_a = InputTensor {
             name: "A".to_string(),
-            dims: 2,
+            formats: vec![LevelFormat::Compressed; 2],
         };
         let tensor_b = InputTensor {
             name: "B".to_string(),
-            dims: 2,
+            formats: vec![LevelFormat::Compressed; 2],
         };
         let output = matmul(tensor_a.stage(), tensor_b.stage());
-        let result = (output.comp)(root, &scope);
+        let result = (output.comp)(root, &scope).unwrap();
         println!("{result:?}");
 
         scope.borrow_mut().print();
@@ -180,18 +386,18 @@ mod test {
         let root = SamOps::Root.stage(&scope)[0];
         let tensor_a = InputTensor {
             name: "A".to_string(),
-            dims: 2,
+            formats: vec![LevelFormat::Compressed; 2],
         };
         let tensor_b = InputTensor {
             name: "B".to_string(),
-            dims: 2,
+            formats: vec![LevelFormat::Compressed; 2],
         };
         let tensor_c = InputTensor {
             name: "C".to_string(),
-            dims: 2,
+            formats: vec![LevelFormat::Compressed; 2],
         };
         let output = matmul(matmul(tensor_a.stage(), tensor_b.stage()), tensor_c.stage());
-        let result = (output.comp)(root, &scope);
+        let result = (output.comp)(root, &scope).unwrap();
         println!("{result:?}");
 
         scope.borrow_mut().print();
@@ -203,4 +409,96 @@ mod test {
                 .print(&mut PrinterContext::default())
         );
     }
+
+    #[test]
+    fn test_dataflows() {
+        let a = vec![
+            (vec![0, 0], 1.0),
+            (vec![0, 2], 2.0),
+            (vec![1, 1], 3.0),
+            (vec![3, 0], 4.0),
+        ];
+        let b = vec![(vec![0, 1], 5.0), (vec![2, 1], 6.0), (vec![2, 3], 7.0)];
+        let matrix =
+            |entries: &[(Vec<usize>, f64)]| SparseTensor::from_coo(vec![4, 4], entries.to_vec());
+        let column_major = |entries: &[(Vec<usize>, f64)]| {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(coords, val)| (vec![coords[1], coords[0]], *val))
+                .collect();
+            SparseTensor::from_coo(vec![4, 4], entries)
+        };
+        // Each dataflow reads its operands stored as it declares.
+        for (dataflow, a, b) in [
+            (Dataflow::InnerProduct, matrix(&a), column_major(&b)),
+            (Dataflow::OuterProduct, column_major(&a), matrix(&b)),
+            (Dataflow::Gustavson, matrix(&a), matrix(&b)),
+        ] {
+            let output = matmul_with(dataflow, input("A").stage(), input("B").stage());
+            let inputs = named([("A", a), ("B", b)]);
+            let scope = ScopeRef::default();
+            let product = stage_and_execute(output, &[Compressed; 2], vec![4, 4], &inputs, &scope);
+            // Row 1 of A only hits the empty row 1 of B.
+            assert_eq!(
+                product.to_coo(),
+                vec![(vec![0, 1], 17.0), (vec![0, 3], 14.0), (vec![3, 1], 20.0)],
+                "{dataflow:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_memoized_graph() {
+        // Stages a chain of products, counting the level lookups of its
+        // operands, and lists the ops staged.
+        let staged = |memoized: bool| {
+            let lookups = Rc::new(Cell::new(0));
+            let leaf = |depth: usize| {
+                let tensor = InputTensor {
+                    name: format!("A{depth}"),
+                    formats: vec![Compressed; 2],
+                }
+                .stage();
+                let counted = |meta: MetaFn| {
+                    let lookups = lookups.clone();
+                    Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
+                        lookups.set(lookups.get() + 1);
+                        meta(refstream, scope)
+                    }) as MetaFn
+                };
+                Tensor {
+                    meta: tensor.meta.into_iter().map(counted).collect(),
+                    comp: tensor.comp,
+                }
+            };
+            let mut chain = leaf(0);
+            for depth in 1..5 {
+                let product = dot_products(chain, leaf(depth));
+                // Read back like `inner_product` does, with or without
+                // memoizing the product's own closures.
+                chain = if memoized {
+                    product.memoized()
+                } else {
+                    product
+                }
+                .buffered();
+            }
+            let scope = ScopeRef::<SamOps>::default();
+            let root = SamOps::Root.stage(&scope)[0];
+            chain.stage_output("X", &[Compressed; 2], root, &scope);
+            let ops: Vec<_> = scope
+                .borrow()
+                .program_order()
+                .map(|(op, syms)| format!("{syms:?} = {op:?}"))
+                .collect();
+            (ops, lookups.get())
+        };
+        let (memoized_ops, memoized_lookups) = staged(true);
+        let (ops, lookups) = staged(false);
+        assert_eq!(memoized_ops, ops);
+        assert!(
+            memoized_lookups < lookups,
+            "{memoized_lookups} lookups memoized, {lookups} otherwise"
+        );
+    }
 }
@@ -0,0 +1,292 @@
+use std::{
+    fs::File,
+    io::{
+        self, BufRead, BufReader,
+        ErrorKind::{InvalidData, InvalidInput},
+    },
+    path::Path,
+};
+
+use crate::{
+    sam::LevelFormat,
+    storage::{invalid, SparseTensor},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Real,
+    Integer,
+    Pattern,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+}
+
+/// Loads a Matrix Market file into a matrix stored as `formats`, e.g.
+/// `&[Dense, Compressed]` for CSR.
+pub fn load_mtx(path: impl AsRef<Path>, formats: &[LevelFormat]) -> io::Result<SparseTensor> {
+    read_mtx(BufReader::new(File::open(path)?), formats)
+}
+
+/// Parses a Matrix Market matrix in coordinate or array layout with real,
+/// integer or pattern entries. Symmetric and skew-symmetric matrices are
+/// expanded to both triangles; pattern entries are read as ones.
+pub fn read_mtx(reader: impl BufRead, formats: &[LevelFormat]) -> io::Result<SparseTensor> {
+    if formats.len() != 2 {
+        return Err(invalid(
+            InvalidInput,
+            format!("Expected 2 level formats, got {}", formats.len()),
+        ));
+    }
+    let mut lines = reader.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| invalid(InvalidData, "Empty Matrix Market file"))??;
+    let words: Vec<_> = header.split_whitespace().map(str::to_lowercase).collect();
+    let [banner, object, layout, field, symmetry] = &words[..] else {
+        return Err(invalid(
+            InvalidData,
+            format!("Malformed Matrix Market header: {header}"),
+        ));
+    };
+    if banner != "%%matrixmarket" || object != "matrix" {
+        return Err(invalid(
+            InvalidData,
+            format!("Not a Matrix Market matrix: {header}"),
+        ));
+    }
+    let coordinate = match layout.as_str() {
+        "coordinate" => true,
+        "array" => false,
+        other => return Err(invalid(InvalidData, format!("Unsupported layout {other}"))),
+    };
+    let field = match field.as_str() {
+        "real" | "double" => Field::Real,
+        "integer" => Field::Integer,
+        "pattern" if coordinate => Field::Pattern,
+        other => return Err(invalid(InvalidData, format!("Unsupported field {other}"))),
+    };
+    let symmetry = match symmetry.as_str() {
+        "general" => Symmetry::General,
+        "symmetric" => Symmetry::Symmetric,
+        "skew-symmetric" => Symmetry::SkewSymmetric,
+        other => {
+            return Err(invalid(
+                InvalidData,
+                format!("Unsupported symmetry {other}"),
+            ))
+        }
+    };
+
+    let mut data = vec![];
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('%') {
+            data.push(line.to_string());
+        }
+    }
+    let mut data = data.into_iter();
+    let size_line = data
+        .next()
+        .ok_or_else(|| invalid(InvalidData, "Missing size line"))?;
+    let sizes = parse_all::<usize>(&size_line)?;
+    let (rows, cols, count) = match (coordinate, &sizes[..]) {
+        (true, &[rows, cols, nnz]) => (rows, cols, nnz),
+        (false, &[rows, cols]) => (rows, cols, array_positions(rows, cols, symmetry).count()),
+        _ => {
+            return Err(invalid(
+                InvalidData,
+                format!("Malformed size line: {size_line}"),
+            ))
+        }
+    };
+
+    let parse_value = |word: &str| -> io::Result<f64> {
+        match field {
+            Field::Real => word
+                .parse()
+                .map_err(|_| invalid(InvalidData, format!("Bad value {word}"))),
+            Field::Integer => word
+                .parse::<i64>()
+                .map(|v| v as f64)
+                .map_err(|_| invalid(InvalidData, format!("Bad integer {word}"))),
+            Field::Pattern => unreachable!(),
+        }
+    };
+    let mut entries = vec![];
+    let mut positions = array_positions(rows, cols, symmetry);
+    for _ in 0..count {
+        let line = data
+            .next()
+            .ok_or_else(|| invalid(InvalidData, format!("Expected {count} entries")))?;
+        let words: Vec<_> = line.split_whitespace().collect();
+        let (row, col, val) = if coordinate {
+            let (row, col, val) = match (field, &words[..]) {
+                (Field::Pattern, [row, col]) => (row, col, 1.0),
+                (Field::Real | Field::Integer, [row, col, val]) => (row, col, parse_value(val)?),
+                _ => return Err(invalid(InvalidData, format!("Malformed entry: {line}"))),
+            };
+            let index = |word: &str, dim: usize| match word.parse::<usize>() {
+                Ok(i) if (1..=dim).contains(&i) => Ok(i - 1),
+                _ => Err(invalid(
+                    InvalidData,
+                    format!("Index {word} is out of bounds in: {line}"),
+                )),
+            };
+            (index(row, rows)?, index(col, cols)?, val)
+        } else {
+            let [val] = &words[..] else {
+                return Err(invalid(InvalidData, format!("Malformed entry: {line}")));
+            };
+            let (row, col) = positions.next().unwrap();
+            (row, col, parse_value(val)?)
+        };
+        if !coordinate && val == 0.0 {
+            continue;
+        }
+        entries.push((vec![row, col], val));
+        if row != col {
+            match symmetry {
+                Symmetry::General => {}
+                Symmetry::Symmetric => entries.push((vec![col, row], val)),
+                Symmetry::SkewSymmetric => entries.push((vec![col, row], -val)),
+            }
+        }
+    }
+    if data.next().is_some() {
+        return Err(invalid(
+            InvalidData,
+            format!("Expected only {count} entries"),
+        ));
+    }
+    // Entries the formats cannot store are the caller's choice of formats.
+    SparseTensor::try_from_coo_with_formats(vec![rows, cols], formats, entries)
+        .map_err(|message| invalid(InvalidInput, message))
+}
+
+fn parse_all<T: std::str::FromStr>(line: &str) -> io::Result<Vec<T>> {
+    line.split_whitespace()
+        .map(|word| {
+            word.parse()
+                .map_err(|_| invalid(InvalidData, format!("Bad number {word}")))
+        })
+        .collect()
+}
+
+/// The positions of an array layout's values in column-major order, keeping
+/// only the lower triangle of symmetric matrices (without the diagonal when
+/// skew-symmetric).
+fn array_positions(
+    rows: usize,
+    cols: usize,
+    symmetry: Symmetry,
+) -> impl Iterator<Item = (usize, usize)> {
+    (0..cols).flat_map(move |col| {
+        let first = match symmetry {
+            Symmetry::General => 0,
+            Symmetry::Symmetric => col,
+            Symmetry::SkewSymmetric => col + 1,
+        };
+        (first..rows).map(move |row| (row, col))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind};
+
+    use crate::sam::LevelFormat::{self, Compressed, Dense, Singleton};
+
+    use super::read_mtx;
+
+    const DCSR: &[LevelFormat] = &[Compressed, Compressed];
+
+    #[test]
+    fn test_coordinate_general() {
+        let file = "%%MatrixMarket matrix coordinate real general
+% a comment
+3 3 3
+1 1 1.5
+3 2 -2
+1 3 4e0
+";
+        let tensor = read_mtx(Cursor::new(file), DCSR).unwrap();
+        assert_eq!(tensor.shape, vec![3, 3]);
+        assert_eq!(
+            tensor.to_coo(),
+            vec![(vec![0, 0], 1.5), (vec![0, 2], 4.0), (vec![2, 1], -2.0)]
+        );
+    }
+
+    #[test]
+    fn test_symmetric_pattern() {
+        let file = "%%MatrixMarket matrix coordinate pattern symmetric
+2 2 2
+1 1
+2 1
+";
+        let tensor = read_mtx(Cursor::new(file), &[Dense, Compressed]).unwrap();
+        assert_eq!(
+            tensor.to_coo(),
+            vec![(vec![0, 0], 1.0), (vec![0, 1], 1.0), (vec![1, 0], 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_array() {
+        let file = "%%MatrixMarket matrix array integer general
+2 2
+1
+0
+3
+4
+";
+        let tensor = read_mtx(Cursor::new(file), DCSR).unwrap();
+        assert_eq!(
+            tensor.to_coo(),
+            vec![(vec![0, 0], 1.0), (vec![0, 1], 3.0), (vec![1, 1], 4.0)]
+        );
+
+        let file = "%%MatrixMarket matrix array real symmetric
+2 2
+1
+2
+3
+";
+        let tensor = read_mtx(Cursor::new(file), DCSR).unwrap();
+        assert_eq!(
+            tensor.to_coo(),
+            vec![
+                (vec![0, 0], 1.0),
+                (vec![0, 1], 2.0),
+                (vec![1, 0], 2.0),
+                (vec![1, 1], 3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let read = |file: &str| read_mtx(Cursor::new(file.to_string()), DCSR);
+        assert!(read("%%MatrixMarket matrix coordinate complex general\n1 1 0\n").is_err());
+        assert!(read("%%MatrixMarket matrix coordinate real general\n2 2 2\n1 1 1\n").is_err());
+        assert!(read("%%MatrixMarket matrix coordinate real general\n2 2 1\n3 1 1\n").is_err());
+        assert!(read("%%MatrixMarket matrix coordinate real general\n2 2 1\n1 1\n").is_err());
+        let file = "%%MatrixMarket matrix coordinate real general\n1 1 0\n";
+        let err = read_mtx(Cursor::new(file), &[Compressed]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        // A singleton level under a dense one needs exactly one entry per row.
+        let singleton = |file: &str| read_mtx(Cursor::new(file.to_string()), &[Dense, Singleton]);
+        let file = "%%MatrixMarket matrix coordinate real general\n2 2 1\n1 1 1\n";
+        assert_eq!(singleton(file).unwrap_err().kind(), ErrorKind::InvalidInput);
+        let file = "%%MatrixMarket matrix coordinate real general\n1 2 2\n1 1 1\n1 2 1\n";
+        assert_eq!(singleton(file).unwrap_err().kind(), ErrorKind::InvalidInput);
+        let file = "%%MatrixMarket matrix coordinate real general\n2 2 2\n1 2 1\n2 1 1\n";
+        assert!(singleton(file).is_ok());
+    }
+}
@@ -0,0 +1,321 @@
+use std::{iter::Peekable, str::CharIndices};
+
+use crate::{
+    lower::{lower_with, IndexExpr, LowerError},
+    schedule::Schedule,
+    tensor::{InputTensor, Tensor},
+};
+
+/// A tensor accessed with one index per level, e.g. `A(i,k)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Access {
+    pub tensor: String,
+    pub indices: Vec<char>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notation {
+    Access(Access),
+    Mul(Box<Notation>, Box<Notation>),
+    Add(Box<Notation>, Box<Notation>),
+}
+
+/// An assignment such as `X(i,j) = A(i,k) * B(j,k) + C(i,j)`. Indices that do
+/// not index the output are summed over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub output: Access,
+    pub expr: Notation,
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn error(&mut self, expected: &str) -> LowerError {
+        let found = match self.chars.peek() {
+            Some(&(at, c)) => format!("{c:?} at {at}"),
+            None => "end of input".to_string(),
+        };
+        LowerError::Syntax(format!(
+            "expected {expected}, found {found} in {:?}",
+            self.source
+        ))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        self.chars.next_if(|&(_, c)| c == expected).is_some()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), LowerError> {
+        match self.eat(expected) {
+            true => Ok(()),
+            false => Err(self.error(&format!("{expected:?}"))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, LowerError> {
+        self.skip_whitespace();
+        let mut name = String::new();
+        while let Some((_, c)) = self.chars.next_if(|&(_, c)| {
+            c.is_ascii_alphabetic() || (!name.is_empty() && (c.is_ascii_alphanumeric() || c == '_'))
+        }) {
+            name.push(c);
+        }
+        match name.is_empty() {
+            true => Err(self.error("a name")),
+            false => Ok(name),
+        }
+    }
+
+    fn access(&mut self) -> Result<Access, LowerError> {
+        let tensor = self.name()?;
+        let mut indices = vec![];
+        if self.eat('(') {
+            loop {
+                let index = self.name()?;
+                let mut chars = index.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => indices.push(c),
+                    _ => {
+                        return Err(LowerError::Syntax(format!(
+                            "index {index} of {tensor} is not a single letter"
+                        )))
+                    }
+                }
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            self.expect(')')?;
+        }
+        Ok(Access { tensor, indices })
+    }
+
+    fn factor(&mut self) -> Result<Notation, LowerError> {
+        if self.eat('(') {
+            let expr = self.sum()?;
+            self.expect(')')?;
+            Ok(expr)
+        } else {
+            Ok(Notation::Access(self.access()?))
+        }
+    }
+
+    fn product(&mut self) -> Result<Notation, LowerError> {
+        let mut expr = self.factor()?;
+        while self.eat('*') {
+            expr = Notation::Mul(Box::new(expr), Box::new(self.factor()?));
+        }
+        Ok(expr)
+    }
+
+    fn sum(&mut self) -> Result<Notation, LowerError> {
+        let mut expr = self.product()?;
+        while self.eat('+') {
+            expr = Notation::Add(Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+}
+
+/// Parses an index-notation assignment. Tensors are named by identifiers and
+/// indexed by single letters; `*` binds tighter than `+`.
+pub fn parse(source: &str) -> Result<Assignment, LowerError> {
+    let mut parser = Parser {
+        source,
+        chars: source.char_indices().peekable(),
+    };
+    let output = parser.access()?;
+    parser.expect('=')?;
+    let expr = parser.sum()?;
+    parser.skip_whitespace();
+    if parser.chars.peek().is_some() {
+        return Err(parser.error("'+', '*' or the end"));
+    }
+    Ok(Assignment { output, expr })
+}
+
+impl Assignment {
+    /// Lowers the right-hand side over `inputs`, which are looked up by name
+    /// and read in their storage order.
+    pub fn lower(&self, inputs: &[&InputTensor]) -> Result<Tensor, LowerError> {
+        self.lower_with(inputs, &Schedule::default())
+    }
+
+    /// Lowers the right-hand side like `lower`, staging its loops as `schedule`
+    /// says.
+    pub fn lower_with(
+        &self,
+        inputs: &[&InputTensor],
+        schedule: &Schedule,
+    ) -> Result<Tensor, LowerError> {
+        let mut used: Vec<&InputTensor> = vec![];
+        let expr = self.index_expr(&self.expr, inputs, &mut used)?;
+        let operands = used.iter().map(|input| input.stage()).collect();
+        lower_with(expr, operands, &self.output.indices, schedule)
+    }
+
+    fn index_expr<'a>(
+        &self,
+        expr: &Notation,
+        inputs: &[&'a InputTensor],
+        used: &mut Vec<&'a InputTensor>,
+    ) -> Result<IndexExpr, LowerError> {
+        Ok(match expr {
+            Notation::Access(Access { tensor, indices }) => {
+                let input = inputs
+                    .iter()
+                    .find(|input| input.name == *tensor)
+                    .ok_or_else(|| LowerError::UndefinedTensor(tensor.clone()))?;
+                if indices.len() != input.dims() {
+                    return Err(LowerError::Rank {
+                        operand: tensor.clone(),
+                        expected: input.dims(),
+                        found: indices.len(),
+                    });
+                }
+                let operand = match used.iter().position(|other| other.name == *tensor) {
+                    Some(operand) => operand,
+                    None => {
+                        used.push(input);
+                        used.len() - 1
+                    }
+                };
+                IndexExpr::Access {
+                    operand,
+                    indices: indices.clone(),
+                }
+            }
+            Notation::Mul(lhs, rhs) => IndexExpr::Mul(
+                Box::new(self.index_expr(lhs, inputs, used)?),
+                Box::new(self.index_expr(rhs, inputs, used)?),
+            ),
+            Notation::Add(lhs, rhs) => IndexExpr::Add(
+                Box::new(self.index_expr(lhs, inputs, used)?),
+                Box::new(self.index_expr(rhs, inputs, used)?),
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lower::LowerError,
+        sam::{LevelFormat::Compressed, SamOps},
+        storage::SparseTensor,
+        sym::ScopeRef,
+        tensor::InputTensor,
+        test_util::{matrix_a, named, stage_and_execute},
+    };
+
+    use super::{parse, Access, Notation};
+
+    fn input(name: &str, order: usize) -> InputTensor {
+        InputTensor {
+            name: name.to_string(),
+            formats: vec![Compressed; order],
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let access = |tensor: &str, indices: &str| {
+            Box::new(Notation::Access(Access {
+                tensor: tensor.to_string(),
+                indices: indices.chars().collect(),
+            }))
+        };
+        let assignment = parse("X(i,j) = A(i,k) * (B(j,k) + C(j, k)) + d").unwrap();
+        assert_eq!(assignment.output.tensor, "X");
+        assert_eq!(
+            assignment.expr,
+            Notation::Add(
+                Box::new(Notation::Mul(
+                    access("A", "ik"),
+                    Box::new(Notation::Add(access("B", "jk"), access("C", "jk")))
+                )),
+                access("d", "")
+            )
+        );
+    }
+
+    #[test]
+    fn test_lower() {
+        let assignment = parse("X(i,j) = A(i,k) * B(k,j) + C(i,j)").unwrap();
+        let (a, b, c) = (input("A", 2), input("B", 2), input("C", 2));
+        let output = assignment.lower(&[&a, &b, &c]).unwrap();
+
+        let b = SparseTensor::from_coo(
+            vec![3, 3],
+            vec![(vec![0, 0], 4.0), (vec![1, 2], 5.0), (vec![2, 0], 1.0)],
+        );
+        let c = SparseTensor::from_coo(vec![3, 3], vec![(vec![1, 1], 1.0), (vec![2, 1], 2.0)]);
+        let inputs = named([("A", matrix_a()), ("B", b), ("C", c)]);
+        let scope = ScopeRef::default();
+        let found = stage_and_execute(output, &[Compressed; 2], vec![3, 3], &inputs, &scope);
+        // The sum over k encloses the loop over j, so its partial products
+        // are merged before C is added.
+        assert!(scope
+            .borrow()
+            .program_order()
+            .any(|(expr, _)| matches!(expr, SamOps::Spacc { .. })));
+        assert_eq!(
+            found.to_coo(),
+            vec![
+                (vec![0, 0], 6.0),
+                (vec![1, 1], 1.0),
+                (vec![2, 1], 2.0),
+                (vec![2, 2], 15.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let lower = |source: &str| {
+            let (a, b) = (input("A", 2), input("b", 1));
+            parse(source).and_then(|assignment| assignment.lower(&[&a, &b]))
+        };
+        assert!(matches!(
+            lower("X(i) = A(i,j) *"),
+            Err(LowerError::Syntax(_))
+        ));
+        assert!(matches!(
+            lower("X(i) = A(i,jj)"),
+            Err(LowerError::Syntax(_))
+        ));
+        assert!(matches!(
+            lower("X(i) = A(i,j) b(j)"),
+            Err(LowerError::Syntax(_))
+        ));
+        assert_eq!(
+            lower("X(i) = A(i,j) * c(j)").err(),
+            Some(LowerError::UndefinedTensor("c".to_string()))
+        );
+        assert_eq!(
+            lower("X(i) = A(i) * b(i)").err(),
+            Some(LowerError::Rank {
+                operand: "A".to_string(),
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            lower("X(i,k) = A(i,j) * b(j)").err(),
+            Some(LowerError::UnboundIndex('k'))
+        );
+        assert_eq!(
+            lower("X(i,j) = A(i,j) + b(i)").err(),
+            Some(LowerError::Broadcast('j'))
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    fmt::{self, Debug},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    rewrite::{identities, Rewriter},
+    sam::SamOps,
+    sym::{Expr, Scope, StageError, Sym},
+};
+
+/// A scope along with the streams it computes and what passes have worked
+/// out about it.
+#[derive(Debug)]
+pub struct Program<T> {
+    pub scope: Scope<T>,
+    pub roots: Vec<Sym>,
+    /// The FIFO depth of each stream, as `SimConfig::fifo_depths` takes them.
+    /// Passes that rebuild the scope clear it.
+    pub fifo_depths: FxHashMap<Sym, usize>,
+}
+
+impl<T> Program<T> {
+    pub fn new(scope: Scope<T>, roots: Vec<Sym>) -> Self {
+        Self {
+            scope,
+            roots,
+            fifo_depths: FxHashMap::default(),
+        }
+    }
+}
+
+pub trait Pass<T> {
+    fn name(&self) -> &'static str;
+
+    fn run(&self, program: &mut Program<T>) -> Result<(), StageError>;
+}
+
+fn rebuild<T>(program: &mut Program<T>, rewriter: &Rewriter<T>) -> Result<(), StageError>
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    let rewritten = rewriter.run(&program.scope, &program.roots)?;
+    program.scope = rewritten.scope;
+    program.roots = rewritten.roots;
+    program.fifo_depths.clear();
+    Ok(())
+}
+
+/// Rebuilds the scope with the rules of a `Rewriter`.
+pub struct Simplify<T>(pub Rewriter<T>);
+
+impl<T> Pass<T> for Simplify<T>
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    fn name(&self) -> &'static str {
+        "simplify"
+    }
+
+    fn run(&self, program: &mut Program<T>) -> Result<(), StageError> {
+        rebuild(program, &self.0)
+    }
+}
+
+/// Rebuilds the scope without rules, staging every op again once its inputs
+/// are. Ops that `Expr::simplify` canonicalizes to the same op, or that read
+/// streams aliased to the same one, are merged.
+pub struct Cse;
+
+impl<T> Pass<T> for Cse
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    fn name(&self) -> &'static str {
+        "cse"
+    }
+
+    fn run(&self, program: &mut Program<T>) -> Result<(), StageError> {
+        rebuild(program, &Rewriter::default())
+    }
+}
+
+/// Removes the ops the roots do not depend on.
+pub struct Dce;
+
+impl<T> Pass<T> for Dce
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, program: &mut Program<T>) -> Result<(), StageError> {
+        let roots = program.roots.iter().copied().collect();
+        program.scope.eliminate_dead_code(roots);
+        Ok(())
+    }
+}
+
+/// Checks the scope with `Scope::verify`, and that the roots are staged.
+pub struct Verify;
+
+impl<T> Pass<T> for Verify
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    fn name(&self) -> &'static str {
+        "verify"
+    }
+
+    fn run(&self, program: &mut Program<T>) -> Result<(), StageError> {
+        program.scope.verify()?;
+        match program
+            .roots
+            .iter()
+            .find(|root| program.scope.stream_type(**root).is_none())
+        {
+            Some(root) => Err(StageError::Unstaged(*root)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sizes FIFOs to balance reconvergent paths. Every op sits a stage past the
+/// latest of its inputs; an op reading a stream several stages after it was
+/// produced has to buffer the tokens its other inputs take to arrive, so the
+/// FIFO gets `depth` tokens per stage crossed.
+pub struct FifoSizing {
+    pub depth: usize,
+}
+
+impl<T> Pass<T> for FifoSizing
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    fn name(&self) -> &'static str {
+        "fifo-sizing"
+    }
+
+    fn run(&self, program: &mut Program<T>) -> Result<(), StageError> {
+        let mut stages: FxHashMap<Sym, usize> = FxHashMap::default();
+        let mut crossed: FxHashMap<Sym, usize> = FxHashMap::default();
+        for (expr, syms) in program.scope.program_order() {
+            let inputs = expr.inputs();
+            let stage = inputs.iter().map(|sym| stages[sym] + 1).max().unwrap_or(0);
+            for sym in inputs {
+                let span = stage - stages[&sym];
+                let entry = crossed.entry(sym).or_default();
+                *entry = span.max(*entry);
+            }
+            stages.extend(syms.iter().map(|sym| (*sym, stage)));
+        }
+        program.fifo_depths = crossed
+            .into_iter()
+            .map(|(sym, span)| (sym, span * self.depth))
+            .collect();
+        Ok(())
+    }
+}
+
+/// What running one pass did to a program.
+#[derive(Debug, Clone)]
+pub struct PassReport {
+    pub pass: &'static str,
+    pub elapsed: Duration,
+    pub ops_before: usize,
+    pub ops_after: usize,
+    /// The scope after the pass, as `Scope::dump` prints it, when the pass
+    /// manager dumps IR.
+    pub ir: Option<String>,
+}
+
+impl fmt::Display for PassReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} ops in {:?}",
+            self.pass, self.ops_before, self.ops_after, self.elapsed
+        )
+    }
+}
+
+/// Runs passes over a program in the order they were added.
+pub struct PassManager<T> {
+    passes: Vec<Box<dyn Pass<T>>>,
+    dump_ir: bool,
+}
+
+impl<T> Default for PassManager<T> {
+    fn default() -> Self {
+        Self {
+            passes: vec![],
+            dump_ir: false,
+        }
+    }
+}
+
+impl PassManager<SamOps> {
+    /// Simplifies with the SAM identities, then merges, prunes and checks
+    /// the ops before sizing their FIFOs `depth` tokens per stage.
+    pub fn standard(depth: usize) -> Self {
+        Self::default()
+            .pass(Simplify(identities()))
+            .pass(Cse)
+            .pass(Dce)
+            .pass(Verify)
+            .pass(FifoSizing { depth })
+    }
+}
+
+impl<T> PassManager<T>
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    pub fn pass(mut self, pass: impl Pass<T> + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Keeps the IR after every pass in its report, for debugging.
+    pub fn dump_ir(mut self, dump_ir: bool) -> Self {
+        self.dump_ir = dump_ir;
+        self
+    }
+
+    /// Runs every pass over `program`, stopping at the first that fails.
+    pub fn run(&self, program: &mut Program<T>) -> Result<Vec<PassReport>, StageError> {
+        let mut reports = vec![];
+        for pass in &self.passes {
+            let ops_before = program.scope.program_order().count();
+            let start = Instant::now();
+            pass.run(program)?;
+            let elapsed = start.elapsed();
+            reports.push(PassReport {
+                pass: pass.name(),
+                elapsed,
+                ops_before,
+                ops_after: program.scope.program_order().count(),
+                ir: self.dump_ir.then(|| program.scope.dump()),
+            });
+        }
+        Ok(reports)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        interpreter::execute,
+        matadd::matadd,
+        matmul::matmul,
+        sam::{LevelFormat::Compressed, SamOps},
+        simulator::{simulate, SimConfig},
+        sym::{Expr, Scope, ScopeRef},
+        tensor::Tensor,
+        test_util::{self, inputs, named},
+    };
+
+    use super::{Dce, PassManager, Program, Verify};
+
+    fn input(name: &str) -> Tensor {
+        test_util::input(name).stage()
+    }
+
+    /// `A @ B + C`, with an unused lookup of `C` staged alongside.
+    fn program() -> Program<SamOps> {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let output = matadd(matmul(input("A"), input("B")), input("C"));
+        let roots = output.stage_output("X", &[Compressed; 2], root, &scope);
+        (input("C").meta[1])(root, &scope).unwrap();
+        Program::new(scope.take(), roots)
+    }
+
+    #[test]
+    fn test_standard_pipeline() {
+        let mut program = program();
+        let shapes = named([("X", vec![3, 3])]);
+        let expected = execute(
+            &program.scope,
+            program.roots.iter().copied().collect(),
+            &inputs(),
+            &shapes,
+        );
+        let reports = PassManager::standard(2)
+            .dump_ir(true)
+            .run(&mut program)
+            .unwrap();
+        let passes: Vec<_> = reports.iter().map(|report| report.pass).collect();
+        assert_eq!(passes, ["simplify", "cse", "dce", "verify", "fifo-sizing"]);
+        // Rebuilding keeps only the ops the outputs depend on.
+        assert!(reports[0].ops_after < reports[0].ops_before);
+        for (report, next) in reports.iter().zip(&reports[1..]) {
+            assert_eq!(report.ops_after, next.ops_before);
+        }
+        let ir = reports.last().unwrap().ir.as_ref().unwrap();
+        assert_eq!(ir, &program.scope.dump());
+        assert!(reports[1].to_string().starts_with("cse: "));
+
+        let roots = program.roots.clone();
+        let found = execute(
+            &program.scope,
+            roots.iter().copied().collect(),
+            &inputs(),
+            &shapes,
+        );
+        assert_eq!(found["X"].to_coo(), expected["X"].to_coo());
+        assert!(program.fifo_depths.values().all(|depth| *depth >= 2));
+        assert!(program.fifo_depths.values().any(|depth| *depth > 2));
+        let config = SimConfig {
+            fifo_depths: program.fifo_depths.clone(),
+            ..Default::default()
+        };
+        let report = simulate(
+            &program.scope,
+            roots.into_iter().collect(),
+            &inputs(),
+            &config,
+        );
+        assert!(!report.deadlocked);
+    }
+
+    #[test]
+    fn test_verify_unstaged_root() {
+        let mut program = program();
+        let reports = PassManager::default()
+            .pass(Dce)
+            .pass(Verify)
+            .run(&mut program)
+            .unwrap();
+        assert!(reports[0].ops_after < reports[0].ops_before);
+        assert!(reports[0].ir.is_none());
+
+        let mut scope = Scope::default();
+        let dead = scope.stage(SamOps::Root)[0];
+        let mut program = Program::new(scope, vec![dead]);
+        program.scope.eliminate_dead_code(Default::default());
+        let err = PassManager::default().pass(Verify).run(&mut program);
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            format!("{dead:?} is not staged in this scope")
+        );
+    }
+}
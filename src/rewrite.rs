@@ -0,0 +1,484 @@
+use std::{
+    fmt::{self, Debug},
+    hash::Hash,
+};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    sam::{JoinType, SamOps},
+    sym::{Expr, Scope, Simplified, StageError, Sym},
+};
+
+/// Rewrites the ops matching a pattern over the op and the producers of its
+/// inputs, which it finds in the scope being rebuilt. Returns what to stage
+/// instead, if anything, after staging any new ops the replacement needs.
+pub type RuleFn<T> = fn(&T, &mut Scope<T>) -> Option<Simplified<T>>;
+
+pub struct Rule<T> {
+    pub name: &'static str,
+    pub rewrite: RuleFn<T>,
+}
+
+/// A rule that fired, rewriting `op` to `result`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firing {
+    pub rule: &'static str,
+    pub op: String,
+    pub result: String,
+}
+
+impl fmt::Display for Firing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} => {}", self.rule, self.op, self.result)
+    }
+}
+
+/// A scope rebuilt by a `Rewriter`, along with the syms of the roots it was
+/// rebuilt from and every rule that fired, in order.
+pub struct Rewritten<T> {
+    pub scope: Scope<T>,
+    pub roots: Vec<Sym>,
+    pub trace: Vec<Firing>,
+}
+
+/// Applies rules to the ops of a scope until none of them fire.
+pub struct Rewriter<T> {
+    rules: Vec<Rule<T>>,
+}
+
+impl<T> Default for Rewriter<T> {
+    fn default() -> Self {
+        Self { rules: vec![] }
+    }
+}
+
+/// How many times one op may be rewritten, and a scope rebuilt, before the
+/// rules are taken to undo one another.
+const MAX_REWRITES: usize = 64;
+
+impl<T> Rewriter<T>
+where
+    T: PartialEq + Eq + Hash + Expr + Debug,
+{
+    /// Adds a rule, tried after the ones added before.
+    pub fn rule(mut self, name: &'static str, rewrite: RuleFn<T>) -> Self {
+        self.rules.push(Rule { name, rewrite });
+        self
+    }
+
+    /// Rebuilds the ops of `scope` that `roots` depend on, rewriting each op,
+    /// once the ops producing its inputs are, until no rule matches it. The
+    /// rebuilt scope is rewritten again until no rule fires.
+    pub fn run(&self, scope: &Scope<T>, roots: &[Sym]) -> Result<Rewritten<T>, StageError> {
+        let mut trace = vec![];
+        let (mut rebuilt, mut roots) = self.pass(scope, roots, &mut trace)?;
+        let mut passes = 1;
+        let mut fired = 0;
+        while trace.len() > fired {
+            assert!(passes < MAX_REWRITES, "Rules keep rewriting the scope");
+            fired = trace.len();
+            (rebuilt, roots) = self.pass(&rebuilt, &roots, &mut trace)?;
+            passes += 1;
+        }
+        Ok(Rewritten {
+            scope: rebuilt,
+            roots,
+            trace,
+        })
+    }
+
+    fn pass(
+        &self,
+        scope: &Scope<T>,
+        roots: &[Sym],
+        trace: &mut Vec<Firing>,
+    ) -> Result<(Scope<T>, Vec<Sym>), StageError> {
+        let live = scope.calculate_live_syms(roots.iter().copied().collect());
+        let mut rebuilt = Scope::default();
+        let mut renamed: FxHashMap<Sym, Sym> = FxHashMap::default();
+        for (expr, syms) in scope.program_order() {
+            if !syms.iter().any(|sym| live.contains(sym)) {
+                continue;
+            }
+            let inputs: Vec<_> = expr.inputs().iter().map(|sym| renamed[sym]).collect();
+            let outputs = self.rewrite(expr.with_inputs(&inputs), &mut rebuilt, trace)?;
+            renamed.extend(syms.iter().copied().zip(outputs));
+        }
+        let roots = roots.iter().map(|root| renamed[root]).collect();
+        Ok((rebuilt, roots))
+    }
+
+    /// Stages `expr` into `scope` once no rule matches it.
+    fn rewrite(
+        &self,
+        mut expr: T,
+        scope: &mut Scope<T>,
+        trace: &mut Vec<Firing>,
+    ) -> Result<Vec<Sym>, StageError> {
+        for _ in 0..MAX_REWRITES {
+            let fired = self
+                .rules
+                .iter()
+                .find_map(|rule| Some((rule.name, (rule.rewrite)(&expr, scope)?)));
+            let Some((rule, result)) = fired else {
+                return scope.try_stage(expr);
+            };
+            trace.push(Firing {
+                rule,
+                op: format!("{expr:?}"),
+                result: format!("{result:?}"),
+            });
+            match result {
+                Simplified::Expr(rewritten) => expr = rewritten,
+                Simplified::Alias(syms) => return scope.alias(&expr, syms),
+                Simplified::Permuted(permuted, ports) => {
+                    let staged = scope.try_stage(permuted)?;
+                    let syms = ports.iter().map(|port| staged[*port]).collect();
+                    return scope.alias(&expr, syms);
+                }
+            }
+        }
+        panic!("Rules keep rewriting {expr:?}")
+    }
+}
+
+/// Rules for SAM graph identities:
+///
+/// - repeat-of-repeat: repeating a repeated stream repeats the original one,
+///   as the repeat stream nests the one it was repeated over.
+/// - join-self: joining a stream with itself yields that stream on every side.
+/// - union-with-empty: a union with the fibers looked up through `Empty`
+///   references yields the other side, and empty references in their place.
+/// - alu-identity: operands that are `Constant`s holding the identity of the
+///   ALU op leave the others unchanged, and are dropped.
+pub fn identities() -> Rewriter<SamOps> {
+    Rewriter::default()
+        .rule("repeat-of-repeat", |expr, scope| {
+            let SamOps::Repeat { target, repeat } = *expr else {
+                return None;
+            };
+            match scope.producer(target)? {
+                (&SamOps::Repeat { target, .. }, _) => {
+                    Some(Simplified::Expr(SamOps::Repeat { target, repeat }))
+                }
+                _ => None,
+            }
+        })
+        .rule("join-self", |expr, _| match *expr {
+            SamOps::Join {
+                ref1,
+                ref2,
+                crd1,
+                crd2,
+                ..
+            } if ref1 == ref2 && crd1 == crd2 => Some(Simplified::Alias(vec![ref1, ref2, crd1])),
+            _ => None,
+        })
+        .rule("union-with-empty", |expr, scope| {
+            let SamOps::Join {
+                ref1,
+                ref2,
+                crd1,
+                crd2,
+                tp: JoinType::Union,
+            } = *expr
+            else {
+                return None;
+            };
+            // Whether the fibers of `reference` and `crd` are looked up
+            // through empty references.
+            let empty = |reference, crd| match scope.producer(reference) {
+                Some((lookup @ SamOps::Fiberlookup { reference, .. }, 0)) => {
+                    scope.producer(crd) == Some((lookup, 1))
+                        && matches!(scope.lookup(*reference), Some(SamOps::Empty { .. }))
+                }
+                _ => false,
+            };
+            let (empty1, empty2) = (empty(ref1, crd1), empty(ref2, crd2));
+            if empty2 {
+                let none = scope.stage(SamOps::Empty { reference: ref1 })[0];
+                Some(Simplified::Alias(vec![ref1, none, crd1]))
+            } else if empty1 {
+                let none = scope.stage(SamOps::Empty { reference: ref2 })[0];
+                Some(Simplified::Alias(vec![none, ref2, crd2]))
+            } else {
+                None
+            }
+        })
+        .rule("alu-identity", |expr, scope| {
+            let SamOps::ALU { op, inputs } = expr else {
+                return None;
+            };
+            let identity = |sym: &Sym| {
+                matches!(
+                    scope.lookup(*sym),
+                    Some(SamOps::Constant { value, .. }) if value.get() == op.identity()
+                )
+            };
+            let kept: Vec<_> = inputs
+                .iter()
+                .copied()
+                .filter(|sym| !identity(sym))
+                .collect();
+            match kept[..] {
+                _ if kept.len() == inputs.len() => None,
+                // The identity combined with itself is the identity.
+                [] => Some(Simplified::Alias(vec![inputs[0]])),
+                [input] => Some(Simplified::Alias(vec![input])),
+                _ => Some(Simplified::Expr(SamOps::ALU {
+                    op: *op,
+                    inputs: kept,
+                })),
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use fxhash::FxHashMap;
+
+    use crate::{
+        einsum::einsum,
+        interpreter::{execute, interpret},
+        sam::{JoinType, LevelFormat::Compressed, PrimitiveOp, SamOps, Scalar},
+        storage::SparseTensor,
+        sym::{Expr, Scope, ScopeRef, Simplified, StageError, Sym},
+        tensor::InputTensor,
+    };
+
+    use super::{identities, Rewriter};
+
+    fn lookup(scope: &mut Scope<SamOps>, tensor: &str, level: usize, reference: Sym) -> [Sym; 2] {
+        let syms = scope.stage(SamOps::Fiberlookup {
+            reference,
+            tensor: tensor.to_string(),
+            level,
+            format: Compressed,
+        });
+        [syms[0], syms[1]]
+    }
+
+    #[test]
+    fn test_identities() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let [a, _] = lookup(&mut scope, "A", 0, root);
+        let [b, _] = lookup(&mut scope, "B", 0, root);
+        let [b1, crd] = lookup(&mut scope, "B", 1, b);
+        let repeated = scope.stage(SamOps::Repeat {
+            target: a,
+            repeat: b,
+        })[0];
+        let twice = scope.stage(SamOps::Repeat {
+            target: repeated,
+            repeat: b1,
+        })[0];
+        let joined = scope.stage(SamOps::Join {
+            ref1: twice,
+            ref2: twice,
+            crd1: crd,
+            crd2: crd,
+            tp: JoinType::Intersect,
+        });
+        let values = scope.stage(SamOps::Arrayval {
+            reference: joined[1],
+            tensor: "A".to_string(),
+        })[0];
+
+        let rewritten = identities().run(&scope, &[values]).unwrap();
+        let fired: Vec<_> = rewritten.trace.iter().map(|firing| firing.rule).collect();
+        assert_eq!(fired, ["repeat-of-repeat", "join-self"]);
+        let ops: Vec<_> = rewritten.scope.program_order().map(|(op, _)| op).collect();
+        assert!(!ops.iter().any(|op| matches!(op, SamOps::Join { .. })));
+        // The original repeat is dead once the second one repeats `a` directly.
+        let repeats = ops
+            .iter()
+            .filter(|op| matches!(op, SamOps::Repeat { .. }))
+            .count();
+        assert_eq!(repeats, 1);
+        let sc = &rewritten.scope;
+        let Some((SamOps::Arrayval { reference, .. }, 0)) = sc.producer(rewritten.roots[0]) else {
+            panic!("Expected the values to stay an Arrayval");
+        };
+        let Some((SamOps::Repeat { target, .. }, 0)) = sc.producer(*reference) else {
+            panic!("Expected the values to read the repeated references");
+        };
+        assert!(matches!(
+            sc.lookup(*target),
+            Some(SamOps::Fiberlookup { tensor, level: 0, .. }) if tensor == "A"
+        ));
+    }
+
+    #[test]
+    fn test_empty_and_constant_identities() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let [a, a_crd] = lookup(&mut scope, "A", 0, root);
+        let none = scope.stage(SamOps::Empty { reference: root })[0];
+        let [e, e_crd] = lookup(&mut scope, "E", 0, none);
+        let joined = scope.stage(SamOps::Join {
+            ref1: a,
+            ref2: e,
+            crd1: a_crd,
+            crd2: e_crd,
+            tp: JoinType::Union,
+        });
+        let [a_vals, e_vals] = [("A", joined[0]), ("E", joined[1])].map(|(tensor, reference)| {
+            scope.stage(SamOps::Arrayval {
+                reference,
+                tensor: tensor.to_string(),
+            })[0]
+        });
+        let sum = scope.stage(SamOps::ALU {
+            op: PrimitiveOp::Add,
+            inputs: vec![a_vals, e_vals],
+        })[0];
+        let one = scope.stage(SamOps::Constant {
+            reference: joined[0],
+            value: Scalar::new(1.0),
+        })[0];
+        let values = scope.stage(SamOps::ALU {
+            op: PrimitiveOp::Mul,
+            inputs: vec![sum, one],
+        })[0];
+
+        let rewritten = identities().run(&scope, &[values]).unwrap();
+        let fired: Vec<_> = rewritten.trace.iter().map(|firing| firing.rule).collect();
+        assert_eq!(fired, ["union-with-empty", "alu-identity"]);
+        let sc = &rewritten.scope;
+        assert!(!sc.program_order().any(|(op, _)| matches!(
+            op,
+            SamOps::Join { .. } | SamOps::Constant { .. }
+        ) || matches!(op, SamOps::Fiberlookup { tensor, .. } if tensor == "E")));
+        assert!(matches!(
+            sc.lookup(rewritten.roots[0]),
+            Some(SamOps::ALU {
+                op: PrimitiveOp::Add,
+                ..
+            })
+        ));
+
+        let inputs: FxHashMap<_, _> = [
+            (
+                "A",
+                SparseTensor::from_coo(vec![3], vec![(vec![0], 2.0), (vec![2], 5.0)]),
+            ),
+            ("E", SparseTensor::from_coo(vec![3], vec![])),
+        ]
+        .into_iter()
+        .map(|(name, tensor)| (name.to_string(), tensor))
+        .collect();
+        let expected = interpret(&scope, [values].into_iter().collect(), &inputs);
+        let root = rewritten.roots[0];
+        let found = interpret(sc, [root].into_iter().collect(), &inputs);
+        assert_eq!(found[&root], expected[&values]);
+    }
+
+    #[test]
+    fn test_custom_rule() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let [reference, _] = lookup(&mut scope, "A", 0, root);
+        let values = scope.stage(SamOps::Arrayval {
+            reference,
+            tensor: "A".to_string(),
+        })[0];
+        // Rename every tensor read, once.
+        let rewriter = Rewriter::default().rule("rename", |expr, _| match expr {
+            SamOps::Fiberlookup {
+                reference,
+                tensor,
+                level,
+                format,
+            } if tensor == "A" => Some(Simplified::Expr(SamOps::Fiberlookup {
+                reference: *reference,
+                tensor: "B".to_string(),
+                level: *level,
+                format: *format,
+            })),
+            _ => None,
+        });
+        let rewritten = rewriter.run(&scope, &[values]).unwrap();
+        assert_eq!(rewritten.trace.len(), 1);
+        assert!(rewritten.trace[0]
+            .to_string()
+            .starts_with("rename: Fiberlookup"));
+        assert!(rewritten
+            .scope
+            .program_order()
+            .any(|(op, _)| matches!(op, SamOps::Fiberlookup { tensor, .. } if tensor == "B")));
+    }
+
+    #[test]
+    fn test_ill_typed_alias() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let [reference, _] = lookup(&mut scope, "A", 0, root);
+        let values = scope.stage(SamOps::Arrayval {
+            reference,
+            tensor: "A".to_string(),
+        })[0];
+        // Replace the values with the references they are read at.
+        let rewriter = Rewriter::default().rule("values-to-refs", |expr, _| match expr {
+            SamOps::Arrayval { reference, .. } => Some(Simplified::Alias(vec![*reference])),
+            _ => None,
+        });
+        let err = rewriter.run(&scope, &[values]).err();
+        assert!(
+            matches!(&err, Some(StageError::Type { op, .. }) if op.starts_with("Arrayval")),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_preserves_results() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let input = |name: &str, order| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![Compressed; order],
+            }
+            .stage()
+        };
+        let output = einsum(
+            "ijk,jr,kr->ir",
+            vec![input("T", 3), input("B", 2), input("C", 2)],
+        )
+        .unwrap();
+        let writers = output.stage_output("X", &[Compressed; 2], root, &scope);
+        let inputs: FxHashMap<_, _> = [
+            (
+                "T",
+                SparseTensor::from_coo(
+                    vec![2, 2, 2],
+                    vec![
+                        (vec![0, 0, 1], 2.0),
+                        (vec![0, 1, 1], 3.0),
+                        (vec![1, 1, 0], 4.0),
+                    ],
+                ),
+            ),
+            (
+                "B",
+                SparseTensor::from_coo(vec![2, 2], vec![(vec![0, 0], 1.0), (vec![1, 1], 5.0)]),
+            ),
+            (
+                "C",
+                SparseTensor::from_coo(vec![2, 2], vec![(vec![1, 0], 7.0), (vec![0, 1], 1.0)]),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, tensor)| (name.to_string(), tensor))
+        .collect();
+
+        let sc = scope.borrow();
+        let rewritten = identities().run(&sc, &writers).unwrap();
+        let shapes = [("X".to_string(), vec![2, 2])].into_iter().collect();
+        let expected = execute(&sc, writers.into_iter().collect(), &inputs, &shapes);
+        let roots = rewritten.roots.into_iter().collect();
+        let found = execute(&rewritten.scope, roots, &inputs, &shapes);
+        assert_eq!(found["X"].to_coo(), expected["X"].to_coo());
+    }
+}
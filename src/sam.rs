@@ -1,4 +1,4 @@
-use crate::sym::{Expr, Sym};
+use crate::sym::{Expr, Simplified, StreamKind, StreamType, Sym};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub enum JoinType {
@@ -6,18 +6,76 @@ pub enum JoinType {
     Union,
 }
 
+impl JoinType {
+    /// Whether swapping the operands only swaps the references output.
+    pub fn is_symmetric(&self) -> bool {
+        match self {
+            JoinType::Intersect | JoinType::Union => true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub enum PrimitiveOp {
     Mul,
     Add,
 }
 
+impl PrimitiveOp {
+    pub fn is_commutative(&self) -> bool {
+        match self {
+            PrimitiveOp::Mul | PrimitiveOp::Add => true,
+        }
+    }
+
+    /// The value that leaves the other operand of `self` unchanged.
+    pub fn identity(&self) -> f64 {
+        match self {
+            PrimitiveOp::Mul => 1.0,
+            PrimitiveOp::Add => 0.0,
+        }
+    }
+}
+
+/// An `f64` compared and hashed by its bits, so that ops can hold one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Scalar(u64);
+
+impl Scalar {
+    pub fn new(value: f64) -> Self {
+        Self(value.to_bits())
+    }
+
+    pub fn get(self) -> f64 {
+        f64::from_bits(self.0)
+    }
+}
+
+impl std::fmt::Debug for Scalar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+/// How a tensor level is stored, which decides the scanner used to read it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+pub enum LevelFormat {
+    /// Every coordinate up to the dimension size is present and generated
+    /// rather than read.
+    Dense,
+    Compressed,
+    /// Exactly one coordinate per parent position, as in the lower levels of COO.
+    Singleton,
+    Bitvector,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum SamOps {
     Fiberlookup {
         reference: Sym,
         tensor: String,
         level: usize,
+        format: LevelFormat,
     },
     Repeat {
         target: Sym,
@@ -37,19 +95,98 @@ pub enum SamOps {
     Reduce {
         inputs: Sym,
         op: PrimitiveOp,
+        /// Whether an empty fiber reduces to the identity of `op` instead of
+        /// producing no value.
+        keep_empty: bool,
     },
     ALU {
         op: PrimitiveOp,
         inputs: Vec<Sym>,
     },
+    /// Outputs the `outer` coordinates whose `inner` fiber is non-empty, and
+    /// the `inner` stream without any empty fibers, including the ones left
+    /// under coordinates dropped further out. With `values`, a value stream
+    /// with the fibers of `inner`, also outputs those values without the
+    /// fibers dropped from `inner`.
     CoordDrop {
         inner: Sym,
         outer: Sym,
+        values: Option<Sym>,
+    },
+    /// A sparse accumulator: sums the values of every `crds` coordinate tuple
+    /// across the fibers of the level just above `crds[0]`, removing that
+    /// level. Outputs the merged coordinates of each `crds` level in sorted
+    /// order, then the summed values.
+    Spacc {
+        crds: Vec<Sym>,
+        values: Sym,
+    },
+    /// Groups the coordinates of each fiber of `crd` into tiles of `factor`
+    /// consecutive coordinates. Like a `Fiberlookup`, outputs a reference to
+    /// each non-empty tile, numbered across the whole stream, and the tile's
+    /// coordinate.
+    Split {
+        crd: Sym,
+        factor: usize,
+    },
+    /// Looks up the tiles that `Split` numbered in `reference`, outputting the
+    /// references and coordinates of the level `level_ref` and `level_crd`
+    /// falling into each.
+    Tilelookup {
+        reference: Sym,
+        level_ref: Sym,
+        level_crd: Sym,
+        factor: usize,
+    },
+    /// Looks up the fibers of a computed level like `Fiberlookup` does those
+    /// of a stored one. Each reference numbers a coordinate of `outer`, the
+    /// level above, whose fiber of `crds` is output along with references
+    /// numbering the coordinates of `crds` in turn. Without `outer`, `crds`
+    /// is a top level with a single fiber.
+    Streamlookup {
+        reference: Sym,
+        outer: Option<Sym>,
+        crds: Sym,
+    },
+    /// Looks up computed values like `Arrayval` does stored ones, by
+    /// references numbering the coordinates of the last level.
+    Vallookup {
+        reference: Sym,
+        values: Sym,
+    },
+    /// Outputs an empty reference, to a fiber that no tensor has, for each
+    /// reference of `reference`. Fibers looked up through it are empty, as
+    /// they would be in a tensor without any stored entries.
+    Empty {
+        reference: Sym,
+    },
+    /// Outputs `value` for each reference of `reference`, like an `Arrayval`
+    /// reading a tensor that holds `value` everywhere.
+    Constant {
+        reference: Sym,
+        value: Scalar,
+    },
+    /// Passes `reference` through, marking the ops that consume it as
+    /// replicated over `lanes` parallel lanes.
+    Parallel {
+        reference: Sym,
+        lanes: usize,
     },
     Root,
     Genref {
         coords: Sym,
     },
+    /// Writes a coordinate stream out as `level` of the output `tensor`.
+    Fiberwrite {
+        coords: Sym,
+        tensor: String,
+        level: usize,
+        format: LevelFormat,
+    },
+    Valwrite {
+        values: Sym,
+        tensor: String,
+    },
 }
 
 impl Expr for SamOps {
@@ -61,19 +198,25 @@ impl Expr for SamOps {
             SamOps::Join { .. } => 3,
             SamOps::Reduce { .. } => 1,
             SamOps::ALU { .. } => 1,
-            SamOps::CoordDrop { .. } => 1,
+            SamOps::CoordDrop { values, .. } => 2 + values.iter().count(),
+            SamOps::Spacc { crds, .. } => crds.len() + 1,
+            SamOps::Split { .. } => 2,
+            SamOps::Tilelookup { .. } => 2,
+            SamOps::Streamlookup { .. } => 2,
+            SamOps::Vallookup { .. } => 1,
+            SamOps::Empty { .. } => 1,
+            SamOps::Constant { .. } => 1,
+            SamOps::Parallel { .. } => 1,
             SamOps::Root => 1,
             SamOps::Genref { .. } => 1,
+            SamOps::Fiberwrite { .. } => 1,
+            SamOps::Valwrite { .. } => 1,
         }
     }
 
     fn inputs(&self) -> Vec<Sym> {
         match self {
-            SamOps::Fiberlookup {
-                reference,
-                tensor: _,
-                level: _,
-            } => vec![*reference],
+            SamOps::Fiberlookup { reference, .. } => vec![*reference],
             SamOps::Repeat { target, repeat } => vec![*target, *repeat],
             SamOps::Arrayval {
                 reference,
@@ -86,37 +229,268 @@ impl Expr for SamOps {
                 crd2,
                 tp: _,
             } => vec![*ref1, *ref2, *crd1, *crd2],
-            SamOps::Reduce { inputs , .. } => vec![*inputs],
+            SamOps::Reduce { inputs, .. } => vec![*inputs],
             SamOps::ALU { op: _, inputs } => inputs.to_vec(),
-            SamOps::CoordDrop { inner, outer } => vec![*inner, *outer],
+            SamOps::CoordDrop {
+                inner,
+                outer,
+                values,
+            } => [Some(*inner), Some(*outer), *values]
+                .into_iter()
+                .flatten()
+                .collect(),
+            SamOps::Spacc { crds, values } => {
+                let mut inputs = crds.clone();
+                inputs.push(*values);
+                inputs
+            }
+            SamOps::Split { crd, .. } => vec![*crd],
+            SamOps::Tilelookup {
+                reference,
+                level_ref,
+                level_crd,
+                ..
+            } => vec![*reference, *level_ref, *level_crd],
+            SamOps::Streamlookup {
+                reference,
+                outer,
+                crds,
+            } => [Some(*reference), *outer, Some(*crds)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            SamOps::Vallookup { reference, values } => vec![*reference, *values],
+            SamOps::Empty { reference }
+            | SamOps::Constant { reference, .. }
+            | SamOps::Parallel { reference, .. } => vec![*reference],
             SamOps::Root => vec![],
             SamOps::Genref { coords } => vec![*coords],
+            SamOps::Fiberwrite { coords, .. } => vec![*coords],
+            SamOps::Valwrite { values, .. } => vec![*values],
+        }
+    }
+
+    fn with_inputs(&self, inputs: &[Sym]) -> Self {
+        let mut replacements = inputs.iter().copied();
+        let mut next = || replacements.next().expect("Too few inputs");
+        let mut expr = self.clone();
+        match &mut expr {
+            SamOps::Fiberlookup { reference, .. }
+            | SamOps::Arrayval { reference, .. }
+            | SamOps::Empty { reference }
+            | SamOps::Constant { reference, .. }
+            | SamOps::Parallel { reference, .. } => *reference = next(),
+            SamOps::Repeat { target, repeat } => {
+                *target = next();
+                *repeat = next();
+            }
+            SamOps::Join {
+                ref1,
+                ref2,
+                crd1,
+                crd2,
+                ..
+            } => {
+                *ref1 = next();
+                *ref2 = next();
+                *crd1 = next();
+                *crd2 = next();
+            }
+            SamOps::Reduce { inputs, .. } => *inputs = next(),
+            SamOps::ALU { inputs, .. } => inputs.iter_mut().for_each(|input| *input = next()),
+            SamOps::CoordDrop {
+                inner,
+                outer,
+                values,
+            } => {
+                *inner = next();
+                *outer = next();
+                if let Some(values) = values {
+                    *values = next();
+                }
+            }
+            SamOps::Spacc { crds, values } => {
+                crds.iter_mut().for_each(|crd| *crd = next());
+                *values = next();
+            }
+            SamOps::Split { crd, .. } => *crd = next(),
+            SamOps::Tilelookup {
+                reference,
+                level_ref,
+                level_crd,
+                ..
+            } => {
+                *reference = next();
+                *level_ref = next();
+                *level_crd = next();
+            }
+            SamOps::Streamlookup {
+                reference,
+                outer,
+                crds,
+            } => {
+                *reference = next();
+                if let Some(outer) = outer {
+                    *outer = next();
+                }
+                *crds = next();
+            }
+            SamOps::Vallookup { reference, values } => {
+                *reference = next();
+                *values = next();
+            }
+            SamOps::Root => {}
+            SamOps::Genref { coords } | SamOps::Fiberwrite { coords, .. } => *coords = next(),
+            SamOps::Valwrite { values, .. } => *values = next(),
+        }
+        expr
+    }
+
+    fn input_kinds(&self) -> Vec<StreamKind> {
+        use StreamKind::*;
+        match self {
+            SamOps::Fiberlookup { .. }
+            | SamOps::Arrayval { .. }
+            | SamOps::Empty { .. }
+            | SamOps::Constant { .. }
+            | SamOps::Parallel { .. } => vec![Ref],
+            SamOps::Repeat { .. } => vec![Ref, Ref],
+            SamOps::Join { .. } => vec![Ref, Ref, Crd, Crd],
+            SamOps::Reduce { .. } | SamOps::Valwrite { .. } => vec![Val],
+            SamOps::ALU { inputs, .. } => vec![Val; inputs.len()],
+            SamOps::CoordDrop { values, .. } => [Some(Crd), Some(Crd), values.map(|_| Val)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            SamOps::Spacc { crds, .. } => {
+                let mut kinds = vec![Crd; crds.len()];
+                kinds.push(Val);
+                kinds
+            }
+            SamOps::Split { .. } | SamOps::Genref { .. } | SamOps::Fiberwrite { .. } => vec![Crd],
+            SamOps::Tilelookup { .. } => vec![Ref, Ref, Crd],
+            SamOps::Streamlookup { outer, .. } => [Some(Ref), outer.map(|_| Crd), Some(Crd)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            SamOps::Vallookup { .. } => vec![Ref, Val],
+            SamOps::Root => vec![],
+        }
+    }
+
+    fn output_types(&self, inputs: &[StreamType]) -> Result<Vec<StreamType>, String> {
+        use StreamKind::*;
+        let stream = |kind, depth| StreamType { kind, depth };
+        let depth = inputs.first().map_or(0, |input| input.depth);
+        let aligned = || match inputs.iter().find(|input| input.depth != depth) {
+            Some(other) => Err(format!(
+                "inputs of depths {depth} and {} are misaligned",
+                other.depth
+            )),
+            None => Ok(()),
+        };
+        // The type of a stream with the fibers of its innermost level merged.
+        let merged = |input: &StreamType| match input.depth {
+            0 => Err("a stream without fibers has no level to merge".to_string()),
+            depth => Ok(stream(input.kind, depth - 1)),
+        };
+        Ok(match self {
+            SamOps::Fiberlookup { .. }
+            | SamOps::Tilelookup { .. }
+            | SamOps::Streamlookup { .. } => vec![stream(Ref, depth + 1), stream(Crd, depth + 1)],
+            SamOps::Repeat { .. } => {
+                let [target, repeat] = [inputs[0], inputs[1]];
+                if target.depth > repeat.depth {
+                    return Err("the target is nested deeper than the repeat stream".to_string());
+                }
+                vec![stream(Ref, repeat.depth)]
+            }
+            SamOps::Arrayval { .. } | SamOps::Vallookup { .. } | SamOps::Constant { .. } => {
+                vec![stream(Val, depth)]
+            }
+            SamOps::Join { .. } => {
+                aligned()?;
+                vec![stream(Ref, depth), stream(Ref, depth), stream(Crd, depth)]
+            }
+            SamOps::Reduce { .. } => vec![merged(&inputs[0])?],
+            SamOps::ALU { .. } => {
+                aligned()?;
+                vec![stream(Val, depth)]
+            }
+            SamOps::CoordDrop { values, .. } => {
+                let mut types = vec![stream(Crd, inputs[1].depth), stream(Crd, depth)];
+                if values.is_some() {
+                    if inputs[2].depth != depth {
+                        return Err(format!(
+                            "values of depth {} do not have the fibers of the inner stream of depth {depth}",
+                            inputs[2].depth
+                        ));
+                    }
+                    types.push(stream(Val, depth));
+                }
+                types
+            }
+            SamOps::Spacc { .. } => inputs.iter().map(merged).collect::<Result<_, _>>()?,
+            SamOps::Split { .. } => vec![stream(Ref, depth), stream(Crd, depth)],
+            SamOps::Empty { .. } | SamOps::Parallel { .. } | SamOps::Genref { .. } => {
+                vec![stream(Ref, depth)]
+            }
+            SamOps::Root => vec![stream(Ref, 0)],
+            SamOps::Fiberwrite { .. } | SamOps::Valwrite { .. } => vec![stream(Signal, 0)],
+        })
+    }
+
+    fn dot_color(&self) -> &'static str {
+        match self {
+            // Accumulators hold state across fibers, unlike the other ops.
+            SamOps::Reduce { .. } | SamOps::Spacc { .. } => "gold",
+            _ => "turquoise",
         }
     }
 
-    fn simplify(self, scope: &crate::sym::Scope<Self>) -> Self
+    fn simplify(self, scope: &crate::sym::Scope<Self>) -> Simplified<Self>
     where
         Self: PartialEq + Eq + std::hash::Hash + Expr + std::fmt::Debug + Sized,
     {
         match self {
-            SamOps::Repeat { target, repeat } => {
-                if let Some(&SamOps::Root) = scope.lookup(repeat) {
-                    dbg!("Eliding Repeat w.r.t. Root!");
-                    scope.lookup(target).unwrap().clone()
-                } else {
-                    self
-                }
+            // The root stream has a single reference, so repeating over it
+            // leaves the target as it is.
+            SamOps::Repeat { target, repeat } if scope.lookup(repeat) == Some(&SamOps::Root) => {
+                Simplified::Alias(vec![target])
+            }
+            // Order the operands of commutative ops by sym, so that swapped
+            // operands stage the same op.
+            SamOps::ALU { op, mut inputs } if op.is_commutative() => {
+                inputs.sort_unstable_by_key(|sym| sym.id);
+                Simplified::Expr(SamOps::ALU { op, inputs })
+            }
+            SamOps::Join {
+                ref1,
+                ref2,
+                crd1,
+                crd2,
+                tp,
+            } if tp.is_symmetric() && (ref2.id, crd2.id) < (ref1.id, crd1.id) => {
+                // The references come out on the ports of their operands.
+                let swapped = SamOps::Join {
+                    ref1: ref2,
+                    ref2: ref1,
+                    crd1: crd2,
+                    crd2: crd1,
+                    tp,
+                };
+                Simplified::Permuted(swapped, vec![1, 0, 2])
             }
-            _ => self,
+            _ => Simplified::Expr(self),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::sym::Scope;
+    use crate::sym::{Scope, StageError, StreamKind, StreamType, Sym};
 
-    use super::SamOps;
+    use super::{JoinType, LevelFormat, PrimitiveOp, SamOps};
 
     #[test]
     fn simple_stage() {
@@ -126,12 +500,205 @@ mod test {
             reference: root,
             tensor: "A".to_string(),
             level: 0,
+            format: LevelFormat::Compressed,
         });
         let _ = scope.stage(SamOps::Fiberlookup {
             reference: read1[0],
             tensor: "A".to_string(),
             level: 1,
+            format: LevelFormat::Compressed,
         });
         scope.print();
     }
+
+    fn lookup(scope: &mut Scope<SamOps>, reference: Sym) -> Vec<Sym> {
+        scope.stage(SamOps::Fiberlookup {
+            reference,
+            tensor: "A".to_string(),
+            level: 0,
+            format: LevelFormat::Compressed,
+        })
+    }
+
+    #[test]
+    fn test_stream_types() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let level = lookup(&mut scope, root);
+        let values = scope.stage(SamOps::Arrayval {
+            reference: level[0],
+            tensor: "A".to_string(),
+        })[0];
+        let sum = scope.stage(SamOps::Reduce {
+            inputs: values,
+            op: PrimitiveOp::Add,
+            keep_empty: false,
+        })[0];
+        let stream = |kind, depth| Some(StreamType { kind, depth });
+        assert_eq!(scope.stream_type(level[0]), stream(StreamKind::Ref, 1));
+        assert_eq!(scope.stream_type(level[1]), stream(StreamKind::Crd, 1));
+        assert_eq!(scope.stream_type(values), stream(StreamKind::Val, 1));
+        assert_eq!(scope.stream_type(sum), stream(StreamKind::Val, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "expects a Ref stream")]
+    fn test_crd_as_reference() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let level = lookup(&mut scope, root);
+        lookup(&mut scope, level[1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expects a Crd stream")]
+    fn test_val_as_crd() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let level = lookup(&mut scope, root);
+        let values = scope.stage(SamOps::Arrayval {
+            reference: level[0],
+            tensor: "A".to_string(),
+        })[0];
+        scope.stage(SamOps::Join {
+            ref1: level[0],
+            ref2: level[0],
+            crd1: values,
+            crd2: level[1],
+            tp: JoinType::Intersect,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "misaligned")]
+    fn test_misaligned_depths() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let level = lookup(&mut scope, root);
+        let [outer, inner] = [root, level[0]].map(|reference| {
+            scope.stage(SamOps::Arrayval {
+                reference,
+                tensor: "A".to_string(),
+            })[0]
+        });
+        scope.stage(SamOps::ALU {
+            op: PrimitiveOp::Mul,
+            inputs: vec![outer, inner],
+        });
+    }
+
+    #[test]
+    fn test_try_stage() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let level = lookup(&mut scope, root);
+        let unstaged = Sym { id: 100 };
+        let reduce = |inputs| SamOps::Reduce {
+            inputs,
+            op: PrimitiveOp::Add,
+            keep_empty: false,
+        };
+        assert_eq!(
+            scope.try_stage(reduce(unstaged)),
+            Err(StageError::Unstaged(unstaged))
+        );
+        let err = scope.try_stage(reduce(level[1])).unwrap_err();
+        assert!(matches!(
+            err,
+            StageError::Kind {
+                port: 0,
+                expected: StreamKind::Val,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "port 0 of {:?} expects a Val stream, got a Crd stream of depth 1",
+                reduce(level[1])
+            )
+        );
+    }
+
+    #[test]
+    fn test_repeat_root_aliases_port() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let level = lookup(&mut scope, root);
+        let values = scope.stage(SamOps::Arrayval {
+            reference: level[0],
+            tensor: "A".to_string(),
+        })[0];
+        // Two tilings of the merged top-level coordinates, joined at depth 0.
+        let merged = scope.stage(SamOps::Spacc {
+            crds: vec![level[1]],
+            values,
+        });
+        let [lhs, rhs] = [2, 3].map(|factor| {
+            scope.stage(SamOps::Split {
+                crd: merged[0],
+                factor,
+            })
+        });
+        let joined = scope.stage(SamOps::Join {
+            ref1: lhs[0],
+            ref2: rhs[0],
+            crd1: lhs[1],
+            crd2: rhs[1],
+            tp: JoinType::Intersect,
+        });
+        let ops = scope.program_order().count();
+        let repeated = scope.stage(SamOps::Repeat {
+            target: joined[1],
+            repeat: root,
+        });
+        assert_eq!(repeated, vec![joined[1]]);
+        assert_eq!(scope.program_order().count(), ops);
+    }
+
+    #[test]
+    fn test_commuted_operands() {
+        let mut scope = Scope::default();
+        let root = scope.stage(SamOps::Root)[0];
+        let a = lookup(&mut scope, root);
+        let b = scope.stage(SamOps::Fiberlookup {
+            reference: root,
+            tensor: "B".to_string(),
+            level: 0,
+            format: LevelFormat::Compressed,
+        });
+        let join = |scope: &mut Scope<SamOps>, lhs: &[Sym], rhs: &[Sym]| {
+            scope.stage(SamOps::Join {
+                ref1: lhs[0],
+                ref2: rhs[0],
+                crd1: lhs[1],
+                crd2: rhs[1],
+                tp: JoinType::Union,
+            })
+        };
+        let ab = join(&mut scope, &a, &b);
+        let ops = scope.program_order().count();
+        let ba = join(&mut scope, &b, &a);
+        assert_eq!(scope.program_order().count(), ops);
+        // Each operand's references still come out on its own port.
+        assert_eq!(ba, vec![ab[1], ab[0], ab[2]]);
+
+        let values = [("A", ab[0]), ("B", ab[1])].map(|(tensor, reference)| {
+            scope.stage(SamOps::Arrayval {
+                reference,
+                tensor: tensor.to_string(),
+            })[0]
+        });
+        let product = |inputs: Vec<Sym>| SamOps::ALU {
+            op: PrimitiveOp::Mul,
+            inputs,
+        };
+        let [va, vb] = values;
+        assert_eq!(
+            scope.stage(product(vec![va, vb])),
+            scope.stage(product(vec![vb, va]))
+        );
+        // Outputs are recorded with the types of the op before simplifying.
+        scope.verify().unwrap();
+    }
 }
@@ -0,0 +1,347 @@
+use std::{fmt::Display, fs, io, io::ErrorKind::InvalidData, path::Path, str::FromStr};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    sam::LevelFormat,
+    storage::{invalid, Level, SparseTensor},
+    tensor::InputTensor,
+};
+
+fn read_list<T: FromStr>(path: &Path) -> io::Result<Vec<T>> {
+    fs::read_to_string(path)?
+        .split_whitespace()
+        .map(|word| {
+            word.parse().map_err(|_| {
+                invalid(
+                    InvalidData,
+                    format!("Bad entry {word} in {}", path.display()),
+                )
+            })
+        })
+        .collect()
+}
+
+fn write_list<T: Display>(path: &Path, items: &[T]) -> io::Result<()> {
+    fs::write(
+        path,
+        items
+            .iter()
+            .map(|item| format!("{item}\n"))
+            .collect::<String>(),
+    )
+}
+
+/// Checks that `seg` splits `crd` into a fiber for each of `parents`
+/// positions, with strictly increasing coordinates if `unique`, and that every
+/// coordinate of `crd` is below `size`.
+fn check_level(
+    name: &str,
+    k: usize,
+    seg: Option<&[usize]>,
+    crd: &[usize],
+    size: usize,
+    parents: usize,
+    unique: bool,
+) -> io::Result<()> {
+    if let Some(seg) = seg {
+        if seg.len() != parents + 1 {
+            return Err(invalid(
+                InvalidData,
+                format!(
+                    "Mode {k} of tensor {name} has {} segment bounds for {parents} fibers",
+                    seg.len()
+                ),
+            ));
+        }
+        if seg[0] != 0 || seg.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(invalid(
+                InvalidData,
+                format!("Segments of mode {k} of tensor {name} do not start at 0 and increase"),
+            ));
+        }
+        if seg[parents] != crd.len() {
+            return Err(invalid(
+                InvalidData,
+                format!(
+                    "Segments of mode {k} of tensor {name} end at {} but it has {} coordinates",
+                    seg[parents],
+                    crd.len()
+                ),
+            ));
+        }
+        // Joins and bitvector positions rely on sorted, distinct coordinates;
+        // only a level above a singleton one repeats them, once per entry.
+        let decreasing = |pair: &[usize]| match unique {
+            true => pair[0] >= pair[1],
+            false => pair[0] > pair[1],
+        };
+        if let Some(p) = (0..parents).find(|&p| crd[seg[p]..seg[p + 1]].windows(2).any(decreasing))
+        {
+            return Err(invalid(
+                InvalidData,
+                format!(
+                    "Fiber {p} of mode {k} of tensor {name} has unsorted or repeated coordinates"
+                ),
+            ));
+        }
+    } else if crd.len() != parents {
+        return Err(invalid(
+            InvalidData,
+            format!(
+                "Singleton mode {k} of tensor {name} has {} coordinates for {parents} fibers",
+                crd.len()
+            ),
+        ));
+    }
+    match crd.iter().find(|&&c| c >= size) {
+        Some(c) => Err(invalid(
+            InvalidData,
+            format!("Coordinate {c} of mode {k} of tensor {name} is out of bounds of size {size}"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Reads tensor `name` from a directory laid out like the SAM simulator's
+/// fixtures: `tensor_<name>_mode_shape`, a `tensor_<name>_mode_<k>_seg` and
+/// `_crd` file per compressed or bitvector level (only `_crd` for singleton
+/// levels, nothing for dense ones) and `tensor_<name>_mode_vals`.
+pub fn read_tensor(
+    dir: impl AsRef<Path>,
+    name: &str,
+    formats: &[LevelFormat],
+) -> io::Result<SparseTensor> {
+    let dir = dir.as_ref();
+    let file = |suffix: &str| dir.join(format!("tensor_{name}_mode_{suffix}"));
+    let shape: Vec<usize> = read_list(&file("shape"))?;
+    if shape.len() != formats.len() {
+        return Err(invalid(
+            InvalidData,
+            format!(
+                "Tensor {name} has {} modes but {} formats were given",
+                shape.len(),
+                formats.len()
+            ),
+        ));
+    }
+    let mut levels = vec![];
+    // The number of positions of the level above.
+    let mut parents = 1;
+    for (k, (&format, &size)) in formats.iter().zip(&shape).enumerate() {
+        let seg = || read_list::<usize>(&file(&format!("{k}_seg")));
+        let crd = || read_list::<usize>(&file(&format!("{k}_crd")));
+        let unique = formats.get(k + 1) != Some(&LevelFormat::Singleton);
+        levels.push(match format {
+            LevelFormat::Dense => {
+                parents *= size;
+                Level::Dense { size }
+            }
+            LevelFormat::Compressed => {
+                let (seg, crd) = (seg()?, crd()?);
+                check_level(name, k, Some(&seg), &crd, size, parents, unique)?;
+                parents = crd.len();
+                Level::Compressed { seg, crd }
+            }
+            LevelFormat::Singleton => {
+                let crd = crd()?;
+                check_level(name, k, None, &crd, size, parents, unique)?;
+                Level::Singleton { crd }
+            }
+            LevelFormat::Bitvector => {
+                let (seg, crd) = (seg()?, crd()?);
+                check_level(name, k, Some(&seg), &crd, size, parents, unique)?;
+                let mut bits = vec![0u64; (parents * size).div_ceil(64)];
+                for parent in 0..parents {
+                    for &c in &crd[seg[parent]..seg[parent + 1]] {
+                        let bit = parent * size + c;
+                        bits[bit / 64] |= 1 << (bit % 64);
+                    }
+                }
+                parents = crd.len();
+                Level::Bitvector { size, seg, bits }
+            }
+        });
+    }
+    let vals: Vec<f64> = read_list(&file("vals"))?;
+    if vals.len() != parents {
+        return Err(invalid(
+            InvalidData,
+            format!(
+                "Tensor {name} has {} values for {parents} positions",
+                vals.len()
+            ),
+        ));
+    }
+    Ok(SparseTensor {
+        shape,
+        levels,
+        vals,
+    })
+}
+
+/// Writes `tensor` under `name` in the layout read by `read_tensor`.
+pub fn write_tensor(dir: impl AsRef<Path>, name: &str, tensor: &SparseTensor) -> io::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let file = |suffix: &str| dir.join(format!("tensor_{name}_mode_{suffix}"));
+    write_list(&file("shape"), &tensor.shape)?;
+    for (k, level) in tensor.levels.iter().enumerate() {
+        let (seg, crd) = match level {
+            Level::Dense { .. } => continue,
+            Level::Compressed { seg, crd } => (Some(seg.clone()), crd.clone()),
+            Level::Singleton { crd } => (None, crd.clone()),
+            Level::Bitvector { seg, .. } => {
+                let crd = (0..seg.len() - 1)
+                    .flat_map(|parent| level.fiber(parent))
+                    .map(|(c, _)| c)
+                    .collect();
+                (Some(seg.clone()), crd)
+            }
+        };
+        if let Some(seg) = seg {
+            write_list(&file(&format!("{k}_seg")), &seg)?;
+        }
+        write_list(&file(&format!("{k}_crd")), &crd)?;
+    }
+    write_list(&file("vals"), &tensor.vals)
+}
+
+/// Reads every tensor in `inputs` from `dir`, keyed by the names that
+/// `InputTensor::stage` uses in `Fiberlookup` and `Arrayval`.
+pub fn read_inputs(
+    dir: impl AsRef<Path>,
+    inputs: &[&InputTensor],
+) -> io::Result<FxHashMap<String, SparseTensor>> {
+    inputs
+        .iter()
+        .map(|input| {
+            let tensor = read_tensor(&dir, &input.name, &input.formats)?;
+            Ok((input.name.clone(), tensor))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::ErrorKind,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        sam::LevelFormat::{self, Bitvector, Compressed, Dense, Singleton},
+        storage::SparseTensor,
+        tensor::InputTensor,
+    };
+
+    use super::{read_inputs, read_tensor, write_tensor};
+
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("metastage_{test}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn matrix(formats: &[LevelFormat]) -> SparseTensor {
+        SparseTensor::from_coo_with_formats(
+            vec![3, 4],
+            formats,
+            vec![(vec![0, 1], 1.0), (vec![0, 3], 2.0), (vec![2, 2], 3.5)],
+        )
+    }
+
+    /// The files of `matrix(&[Compressed, Compressed])` under the name `B`.
+    const FIXTURE: [(&str, &str); 6] = [
+        ("shape", "3\n4\n"),
+        ("0_seg", "0\n2\n"),
+        ("0_crd", "0\n2\n"),
+        ("1_seg", "0\n2\n3\n"),
+        ("1_crd", "1\n3\n2\n"),
+        ("vals", "1.0\n2.0\n3.5\n"),
+    ];
+
+    fn write_fixture(dir: &Path, files: &[(&str, &str)]) {
+        fs::create_dir_all(dir).unwrap();
+        for (suffix, contents) in files {
+            fs::write(dir.join(format!("tensor_B_mode_{suffix}")), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_fixture() {
+        let dir = scratch_dir("fixture");
+        write_fixture(&dir, &FIXTURE);
+        let tensor = read_tensor(&dir, "B", &[Compressed, Compressed]).unwrap();
+        assert_eq!(tensor, matrix(&[Compressed, Compressed]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_fixture() {
+        let dir = scratch_dir("invalid");
+        for (suffix, contents) in [
+            ("0_seg", "0\n"),
+            ("0_seg", "0\n3\n"),
+            ("1_seg", "0\n3\n2\n"),
+            ("1_crd", "1\n4\n2\n"),
+            ("vals", "1.0\n2.0\n"),
+        ] {
+            write_fixture(&dir, &FIXTURE);
+            write_fixture(&dir, &[(suffix, contents)]);
+            let err = read_tensor(&dir, "B", &[Compressed, Compressed]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{suffix}: {err}");
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        write_fixture(&dir, &FIXTURE);
+        let err = read_tensor(&dir, "B", &[Compressed, Singleton]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unsorted_fibers() {
+        let dir = scratch_dir("unsorted");
+        // Row 0 holds its coordinates out of order, then twice the same one.
+        for (formats, crd) in [
+            ([Compressed, Compressed], "3\n1\n2\n"),
+            ([Compressed, Compressed], "1\n1\n2\n"),
+            ([Compressed, Bitvector], "3\n1\n2\n"),
+            ([Compressed, Bitvector], "1\n1\n2\n"),
+        ] {
+            write_fixture(&dir, &FIXTURE);
+            write_fixture(&dir, &[("1_crd", crd)]);
+            let err = read_tensor(&dir, "B", &formats).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{formats:?}: {err}");
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = scratch_dir("round_trip");
+        for formats in [
+            [Compressed, Compressed],
+            [Dense, Compressed],
+            [Compressed, Singleton],
+            [Dense, Bitvector],
+        ] {
+            let tensor = matrix(&formats);
+            write_tensor(&dir, "A", &tensor).unwrap();
+            let input = InputTensor {
+                name: "A".to_string(),
+                formats: formats.to_vec(),
+            };
+            let inputs = read_inputs(&dir, &[&input]).unwrap();
+            assert_eq!(inputs["A"], tensor);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = scratch_dir("missing");
+        assert!(read_tensor(&dir, "C", &[Compressed]).is_err());
+    }
+}
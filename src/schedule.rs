@@ -0,0 +1,413 @@
+use fxhash::FxHashMap;
+
+use crate::lower::LowerError;
+
+/// One step of a `Schedule`. Loops are named by index variables; `Split` and
+/// `Fuse` name new loops that later steps can refer to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transform {
+    /// Nests the named loops in this order, leaving the placement of the
+    /// others to the lowering.
+    Reorder(Vec<char>),
+    /// Splits loop `index` into an `outer` loop over tiles of `factor`
+    /// consecutive coordinates and an `inner` loop over the coordinates of a
+    /// tile.
+    Split {
+        index: char,
+        outer: char,
+        inner: char,
+        factor: usize,
+    },
+    /// Fuses loop `inner`, which is then nested directly in `outer`, into one
+    /// loop `fused` over their coordinate pairs. Nested streams already visit
+    /// the pairs as one sequence, so fusing changes no streams, only which
+    /// loops the other steps refer to.
+    Fuse {
+        outer: char,
+        inner: char,
+        fused: char,
+    },
+    /// Runs the body of loop `index` on `lanes` parallel lanes.
+    Parallel { index: char, lanes: usize },
+}
+
+/// Transformations of the loop nest an index expression is lowered to, after
+/// TACO's scheduling language, e.g.
+/// `Schedule::default().split('i', 'o', 'p', 4).reorder(&['o', 'p', 'k'])`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub transforms: Vec<Transform>,
+}
+
+impl Schedule {
+    pub fn reorder(mut self, order: &[char]) -> Self {
+        self.transforms.push(Transform::Reorder(order.to_vec()));
+        self
+    }
+
+    pub fn split(mut self, index: char, outer: char, inner: char, factor: usize) -> Self {
+        self.transforms.push(Transform::Split {
+            index,
+            outer,
+            inner,
+            factor,
+        });
+        self
+    }
+
+    pub fn fuse(mut self, outer: char, inner: char, fused: char) -> Self {
+        self.transforms.push(Transform::Fuse {
+            outer,
+            inner,
+            fused,
+        });
+        self
+    }
+
+    pub fn parallel(mut self, index: char, lanes: usize) -> Self {
+        self.transforms.push(Transform::Parallel { index, lanes });
+        self
+    }
+
+    /// Applies the transformations to the loops over `indices`.
+    pub(crate) fn resolve(&self, indices: &[char]) -> Result<Loops, LowerError> {
+        let mut loops = Loops {
+            vars: indices
+                .iter()
+                .map(|index| (*index, vec![(*index, Tiling::Whole)]))
+                .collect(),
+            ..Default::default()
+        };
+        // The loops each name stands for, outermost first.
+        let mut names: FxHashMap<char, Vec<char>> =
+            indices.iter().map(|index| (*index, vec![*index])).collect();
+        let fresh = |names: &FxHashMap<char, Vec<char>>, name: char| match names.contains_key(&name)
+        {
+            true => Err(LowerError::Schedule(format!("loop {name} already exists"))),
+            false => Ok(()),
+        };
+        let expand = |names: &FxHashMap<char, Vec<char>>, name: char| {
+            names
+                .get(&name)
+                .cloned()
+                .ok_or_else(|| LowerError::Schedule(format!("there is no loop {name}")))
+        };
+        for transform in &self.transforms {
+            match *transform {
+                Transform::Reorder(ref order) => {
+                    let mut chain = vec![];
+                    for name in order {
+                        chain.extend(expand(&names, *name)?);
+                    }
+                    loops.order.push(chain);
+                }
+                Transform::Split {
+                    index,
+                    outer,
+                    inner,
+                    factor,
+                } => {
+                    let (source, position) = loops.find(index).ok_or_else(|| {
+                        LowerError::Schedule(format!("loop {index} is not the loop of an index"))
+                    })?;
+                    if loops.vars[&source][position].1 != Tiling::Whole {
+                        return Err(LowerError::Schedule(format!(
+                            "loop {index} is already split"
+                        )));
+                    }
+                    if factor == 0 {
+                        return Err(LowerError::Schedule(format!(
+                            "loop {index} is split into empty tiles"
+                        )));
+                    }
+                    if loops
+                        .fused
+                        .iter()
+                        .any(|pair| pair.0 == index || pair.1 == index)
+                    {
+                        return Err(LowerError::Schedule(format!(
+                            "loop {index} is fused and can't be split"
+                        )));
+                    }
+                    names.remove(&index);
+                    fresh(&names, outer)?;
+                    names.insert(outer, vec![outer]);
+                    fresh(&names, inner)?;
+                    names.insert(inner, vec![inner]);
+                    loops.vars.get_mut(&source).unwrap().splice(
+                        position..position + 1,
+                        [
+                            (outer, Tiling::Outer(factor)),
+                            (inner, Tiling::Inner(factor)),
+                        ],
+                    );
+                    for chain in &mut loops.order {
+                        if let Some(at) = chain.iter().position(|var| *var == index) {
+                            chain.splice(at..at + 1, [outer, inner]);
+                        }
+                    }
+                    if let Some(lanes) = loops.lanes.remove(&index) {
+                        loops.lanes.insert(inner, lanes);
+                    }
+                }
+                Transform::Fuse {
+                    outer,
+                    inner,
+                    fused,
+                } => {
+                    for name in [outer, inner] {
+                        if loops.find(name).is_none() {
+                            return Err(LowerError::Schedule(format!(
+                                "loop {name} is not the loop of an index"
+                            )));
+                        }
+                    }
+                    fresh(&names, fused)?;
+                    names.insert(fused, vec![outer, inner]);
+                    loops.fused.push((outer, inner));
+                }
+                Transform::Parallel { index, lanes } => {
+                    if lanes == 0 {
+                        return Err(LowerError::Schedule(format!(
+                            "loop {index} runs on no lanes"
+                        )));
+                    }
+                    // The body of a fused loop is the body of its inner loop.
+                    let vars = expand(&names, index)?;
+                    loops.lanes.insert(vars[vars.len() - 1], lanes);
+                }
+            }
+        }
+        Ok(loops)
+    }
+}
+
+/// How a loop visits the level of the index it is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Tiling {
+    Whole,
+    /// The tiles of the level.
+    Outer(usize),
+    /// The coordinates of the level within a tile.
+    Inner(usize),
+}
+
+/// The loops a schedule visits an expression's indices with.
+#[derive(Debug, Default)]
+pub(crate) struct Loops {
+    /// The loops visiting each index, outermost first.
+    pub vars: FxHashMap<char, Vec<(char, Tiling)>>,
+    /// Loops that have to nest in the order listed.
+    pub order: Vec<Vec<char>>,
+    /// Pairs of loops where the second has to nest directly in the first.
+    pub fused: Vec<(char, char)>,
+    /// The number of lanes of every parallel loop.
+    pub lanes: FxHashMap<char, usize>,
+}
+
+impl Loops {
+    /// The index loop `var` is derived from, and its position among that
+    /// index's loops.
+    fn find(&self, var: char) -> Option<(char, usize)> {
+        self.vars.iter().find_map(|(source, vars)| {
+            vars.iter()
+                .position(|(other, _)| *other == var)
+                .map(|position| (*source, position))
+        })
+    }
+
+    /// The loops visiting `indices`, outermost first.
+    pub fn expand(&self, indices: &[char]) -> Vec<char> {
+        indices
+            .iter()
+            .flat_map(|index| self.vars[index].iter().map(|(var, _)| *var))
+            .collect()
+    }
+
+    /// The index loop `var` is derived from and how it visits its levels.
+    pub fn source(&self, var: char) -> (char, Tiling) {
+        let (source, position) = self.find(var).expect("Unknown loop");
+        (source, self.vars[&source][position].1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxhash::FxHashMap;
+
+    use crate::{
+        einsum::einsum_with,
+        lower::LowerError,
+        notation::parse,
+        sam::{LevelFormat::Compressed, SamOps},
+        storage::SparseTensor,
+        sym::ScopeRef,
+        tensor::{InputTensor, Tensor},
+        test_util::stage_and_execute,
+    };
+
+    use super::Schedule;
+
+    /// The entries of a result and whether any op was staged as parallel.
+    type Run = (Vec<(Vec<usize>, f64)>, bool);
+
+    fn input(name: &str, order: usize) -> InputTensor {
+        InputTensor {
+            name: name.to_string(),
+            formats: vec![Compressed; order],
+        }
+    }
+
+    /// A 6x6 matrix (or vector) with a scattered pattern picked by `seed`.
+    fn tensor(order: usize, seed: usize) -> SparseTensor {
+        let entries = (0..6usize.pow(order as u32)).filter_map(|position| {
+            let coords: Vec<usize> = match order {
+                1 => vec![position],
+                _ => vec![position / 6, position % 6],
+            };
+            let hash = coords.iter().fold(seed, |hash, crd| hash * 7 + crd * 3);
+            (hash % 4 == 0).then_some((coords, (position % 5 + 1) as f64))
+        });
+        SparseTensor::from_coo(vec![6; order], entries)
+    }
+
+    /// Computes `output` over the tensors `names`.
+    fn run(output: Tensor, order: usize, names: &[(&str, usize)]) -> Run {
+        let inputs: FxHashMap<_, _> = names
+            .iter()
+            .enumerate()
+            .map(|(seed, (name, order))| (name.to_string(), tensor(*order, seed)))
+            .collect();
+        let scope = ScopeRef::default();
+        let found = stage_and_execute(
+            output,
+            &vec![Compressed; order],
+            vec![6; order],
+            &inputs,
+            &scope,
+        );
+        let parallel = scope
+            .borrow()
+            .program_order()
+            .any(|(expr, _)| matches!(expr, SamOps::Parallel { .. }));
+        (found.to_coo(), parallel)
+    }
+
+    fn einsum(spec: &str, schedule: &Schedule) -> Result<Run, LowerError> {
+        let (inputs, output) = spec.split_once("->").unwrap();
+        let names: Vec<_> = ["A", "B", "C"]
+            .into_iter()
+            .zip(inputs.split(',').map(str::len))
+            .collect();
+        let operands = names
+            .iter()
+            .map(|(name, order)| input(name, *order).stage())
+            .collect();
+        let tensor = einsum_with(spec, operands, schedule)?;
+        Ok(run(tensor, output.len(), &names))
+    }
+
+    fn check(spec: &str, schedule: Schedule) -> bool {
+        let (expected, _) = einsum(spec, &Schedule::default()).unwrap();
+        let (result, parallel) = einsum(spec, &schedule).unwrap();
+        assert!(!expected.is_empty());
+        assert_eq!(result, expected, "{spec} with {schedule:?}");
+        parallel
+    }
+
+    #[test]
+    fn test_split() {
+        let split = |index| Schedule::default().split(index, 'o', 'p', 2);
+        check("ik,jk->ij", split('i'));
+        check("ik,jk->ij", split('j'));
+        check("ik,jk->ij", split('k'));
+        check("ik,kj->ij", split('k'));
+        check("ij,j->i", split('i').split('j', 'q', 'r', 4));
+    }
+
+    #[test]
+    fn test_split_subexpression() {
+        let assignment = parse("X(i,j) = A(i,k) * B(j,k) + C(i,j)").unwrap();
+        let (a, b, c) = (input("A", 2), input("B", 2), input("C", 2));
+        let names = [("A", 2), ("B", 2), ("C", 2)];
+        let expected = run(assignment.lower(&[&a, &b, &c]).unwrap(), 2, &names);
+        let schedule = Schedule::default().split('k', 'o', 'p', 2);
+        let tensor = assignment.lower_with(&[&a, &b, &c], &schedule).unwrap();
+        assert_eq!(run(tensor, 2, &names), expected);
+    }
+
+    #[test]
+    fn test_reorder() {
+        check("ij,k->ij", Schedule::default().reorder(&['k', 'i']));
+        check("ij,k->ij", Schedule::default().reorder(&['i', 'k', 'j']));
+        check(
+            "ik,jk->ij",
+            Schedule::default()
+                .split('k', 'o', 'p', 3)
+                .reorder(&['i', 'j', 'o', 'p']),
+        );
+        assert!(matches!(
+            einsum("ik,jk->ij", &Schedule::default().reorder(&['k', 'i'])),
+            Err(LowerError::LoopOrder(_))
+        ));
+    }
+
+    #[test]
+    fn test_fuse_parallel() {
+        let fused = Schedule::default().fuse('i', 'j', 'f');
+        assert!(!check("ik,jk->ij", fused.clone()));
+        assert!(check("ik,jk->ij", fused.parallel('f', 4)));
+        assert!(check(
+            "ij,j->i",
+            Schedule::default().split('i', 'o', 'p', 2).parallel('o', 2)
+        ));
+        assert!(matches!(
+            einsum("ik,jk->ij", &Schedule::default().fuse('i', 'k', 'f')),
+            Err(LowerError::LoopOrder(_))
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |schedule: Schedule| match einsum("ik,jk->ij", &schedule) {
+            Err(LowerError::Schedule(message)) => message,
+            other => panic!("Expected a schedule error, got {other:?}"),
+        };
+        assert_eq!(
+            error(Schedule::default().reorder(&['z'])),
+            "there is no loop z"
+        );
+        assert_eq!(
+            error(Schedule::default().split('i', 'j', 'p', 2)),
+            "loop j already exists"
+        );
+        assert_eq!(
+            error(Schedule::default().split('i', 'o', 'p', 0)),
+            "loop i is split into empty tiles"
+        );
+        assert_eq!(
+            error(Schedule::default().split('i', 'o', 'p', 2).reorder(&['i'])),
+            "there is no loop i"
+        );
+        assert_eq!(
+            error(
+                Schedule::default()
+                    .fuse('i', 'j', 'f')
+                    .split('f', 'o', 'p', 2)
+            ),
+            "loop f is not the loop of an index"
+        );
+        assert_eq!(
+            error(
+                Schedule::default()
+                    .fuse('i', 'j', 'f')
+                    .split('i', 'o', 'p', 2)
+            ),
+            "loop i is fused and can't be split"
+        );
+        assert_eq!(
+            error(Schedule::default().parallel('k', 0)),
+            "loop k runs on no lanes"
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{
+    interpreter::{evaluate, Event},
+    sam::SamOps,
+    storage::SparseTensor,
+    sym::{Expr, Scope, Sym},
+};
+
+/// FIFO sizing for a simulation. Every consumer of a stream gets its own FIFO,
+/// sized by `fifo_depths` for that stream or `fifo_depth` otherwise.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub fifo_depth: usize,
+    pub fifo_depths: FxHashMap<Sym, usize>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            fifo_depth: 2,
+            fifo_depths: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeStats {
+    pub label: String,
+    pub outputs: Vec<Sym>,
+    /// Cycles in which the node moved at least one token.
+    pub busy: usize,
+    /// Cycles spent waiting for an input token.
+    pub starved: usize,
+    /// Cycles spent waiting for room in a downstream FIFO.
+    pub blocked: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct EdgeStats {
+    pub source: Sym,
+    /// Index of the consuming node in `SimReport::nodes`.
+    pub consumer: usize,
+    pub port: usize,
+    pub depth: usize,
+    pub max_occupancy: usize,
+    pub mean_occupancy: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimReport {
+    pub cycles: usize,
+    pub nodes: Vec<NodeStats>,
+    pub edges: Vec<EdgeStats>,
+    /// Set when the simulation stopped because no node could make progress.
+    pub deadlocked: bool,
+}
+
+struct Node {
+    events: Vec<Event>,
+    cursor: usize,
+    /// The number of tokens each port may move per cycle.
+    lanes: usize,
+    /// The FIFO feeding each input port.
+    inputs: Vec<usize>,
+    /// The FIFOs fed by each output port.
+    outputs: Vec<Vec<usize>>,
+}
+
+enum Progress {
+    Moved,
+    Starved,
+    Blocked,
+    Finished,
+}
+
+/// Simulates the ops of `scope` that `roots` depend on as hardware blocks joined
+/// by bounded FIFOs.
+///
+/// Each cycle, a block may pop one token from every input port and push one
+/// token to every output port, in the order the functional interpreter
+/// accessed them. Blocks consuming the streams of a `Parallel` op, directly or
+/// through other blocks, are replicated over its lanes and may move that many
+/// tokens per port instead, with that many times the FIFO depth. Pushed
+/// tokens become visible to consumers on the next cycle, and a push needs room
+/// in every FIFO fed by that port at the start of the cycle. Streams without
+/// consumers drain into an unbounded sink.
+pub fn simulate(
+    scope: &Scope<SamOps>,
+    roots: FxHashSet<Sym>,
+    tensors: &FxHashMap<String, SparseTensor>,
+    config: &SimConfig,
+) -> SimReport {
+    let mut nodes = vec![];
+    let mut stats = vec![];
+    let mut lanes: FxHashMap<Sym, usize> = FxHashMap::default();
+    evaluate(scope, roots, tensors, |expr, syms, events| {
+        let inherited = expr.inputs().iter().map(|sym| lanes[sym]).max();
+        let node_lanes = match expr {
+            SamOps::Parallel { lanes, .. } => *lanes,
+            _ => 1,
+        }
+        .max(inherited.unwrap_or(1));
+        lanes.extend(syms.iter().map(|sym| (*sym, node_lanes)));
+        stats.push(NodeStats {
+            label: format!("{expr:?}"),
+            outputs: syms.to_vec(),
+            busy: 0,
+            starved: 0,
+            blocked: 0,
+        });
+        nodes.push((expr.inputs(), events, node_lanes));
+    });
+
+    let mut edges = vec![];
+    let mut consumers: FxHashMap<Sym, Vec<usize>> = FxHashMap::default();
+    for (consumer, (inputs, ..)) in nodes.iter().enumerate() {
+        for (port, source) in inputs.iter().enumerate() {
+            consumers.entry(*source).or_default().push(edges.len());
+            edges.push(EdgeStats {
+                source: *source,
+                consumer,
+                port,
+                // Every lane has its own FIFO.
+                depth: config.fifo_depths.get(source).unwrap_or(&config.fifo_depth) * lanes[source],
+                max_occupancy: 0,
+                mean_occupancy: 0.0,
+            });
+        }
+    }
+    let mut nodes: Vec<_> = nodes
+        .into_iter()
+        .zip(&stats)
+        .scan(0, |first_edge, ((inputs, events, lanes), stats)| {
+            let node = Node {
+                events,
+                cursor: 0,
+                lanes,
+                inputs: (*first_edge..*first_edge + inputs.len()).collect(),
+                outputs: stats
+                    .outputs
+                    .iter()
+                    .map(|sym| consumers.get(sym).cloned().unwrap_or_default())
+                    .collect(),
+            };
+            *first_edge += inputs.len();
+            Some(node)
+        })
+        .collect();
+
+    let mut occupancy = vec![0; edges.len()];
+    let mut total_occupancy = vec![0; edges.len()];
+    let mut cycles = 0;
+    let mut deadlocked = false;
+    loop {
+        let mut delta = vec![0isize; edges.len()];
+        let mut moved = false;
+        let mut finished = true;
+        for (node, stats) in nodes.iter_mut().zip(stats.iter_mut()) {
+            match step(node, &occupancy, &edges, &mut delta) {
+                Progress::Moved => {
+                    stats.busy += 1;
+                    moved = true;
+                }
+                Progress::Starved => stats.starved += 1,
+                Progress::Blocked => stats.blocked += 1,
+                Progress::Finished => continue,
+            }
+            finished = false;
+        }
+        if finished {
+            break;
+        }
+        if !moved {
+            deadlocked = true;
+            break;
+        }
+        for (edge, change) in delta.into_iter().enumerate() {
+            occupancy[edge] = occupancy[edge].checked_add_signed(change).unwrap();
+            total_occupancy[edge] += occupancy[edge];
+            edges[edge].max_occupancy = edges[edge].max_occupancy.max(occupancy[edge]);
+        }
+        cycles += 1;
+    }
+    for (edge, total) in edges.iter_mut().zip(total_occupancy) {
+        edge.mean_occupancy = total as f64 / cycles.max(1) as f64;
+    }
+    SimReport {
+        cycles,
+        nodes: stats,
+        edges,
+        deadlocked,
+    }
+}
+
+/// Advances `node` by one cycle against the FIFO occupancy at the start of the
+/// cycle, recording its pops and pushes in `delta`.
+fn step(
+    node: &mut Node,
+    occupancy: &[usize],
+    edges: &[EdgeStats],
+    delta: &mut [isize],
+) -> Progress {
+    if node.cursor == node.events.len() {
+        return Progress::Finished;
+    }
+    let mut read = vec![0; node.inputs.len()];
+    let mut written = vec![0; node.outputs.len()];
+    let start = node.cursor;
+    let stall = loop {
+        let Some(event) = node.events.get(node.cursor) else {
+            break Progress::Moved;
+        };
+        match *event {
+            Event::Read(port) => {
+                let fifo = node.inputs[port];
+                if read[port] == node.lanes {
+                    break Progress::Moved;
+                }
+                if occupancy[fifo] == read[port] {
+                    break Progress::Starved;
+                }
+                read[port] += 1;
+                delta[fifo] -= 1;
+            }
+            Event::Write(port) => {
+                let fifos = &node.outputs[port];
+                if written[port] == node.lanes {
+                    break Progress::Moved;
+                }
+                if fifos
+                    .iter()
+                    .any(|fifo| occupancy[*fifo] + written[port] >= edges[*fifo].depth)
+                {
+                    break Progress::Blocked;
+                }
+                written[port] += 1;
+                for fifo in fifos {
+                    delta[*fifo] += 1;
+                }
+            }
+        }
+        node.cursor += 1;
+    };
+    if node.cursor > start {
+        Progress::Moved
+    } else {
+        stall
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxhash::FxHashMap;
+
+    use crate::{
+        einsum::{einsum, einsum_with},
+        matadd::matadd,
+        matmul::{matmul, matmul_with, Dataflow},
+        sam::{LevelFormat, SamOps},
+        schedule::Schedule,
+        storage::SparseTensor,
+        sym::{Expr, ScopeRef},
+        tensor::{InputTensor, Tensor},
+    };
+
+    use super::{simulate, SimConfig, SimReport};
+
+    fn inputs() -> FxHashMap<String, SparseTensor> {
+        let identity = SparseTensor::from_coo(vec![3, 3], (0..3).map(|i| (vec![i, i], 1.0)));
+        [
+            ("A".to_string(), identity.clone()),
+            ("B".to_string(), identity),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn run(build: fn(Tensor, Tensor) -> Tensor, config: &SimConfig) -> SimReport {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let [a, b] = ["A", "B"].map(|name| InputTensor {
+            name: name.to_string(),
+            formats: vec![LevelFormat::Compressed; 2],
+        });
+        let output = build(a.stage(), b.stage());
+        let result = (output.comp)(root, &scope).unwrap();
+        let sc = scope.borrow();
+        simulate(&sc, [result].into_iter().collect(), &inputs(), config)
+    }
+
+    #[test]
+    fn test_simulate_matmul() {
+        let report = run(matmul, &SimConfig::default());
+        assert!(!report.deadlocked);
+        assert!(report.cycles > 0);
+        for node in &report.nodes {
+            assert!(node.busy + node.starved + node.blocked <= report.cycles);
+        }
+        for edge in &report.edges {
+            assert!(edge.max_occupancy <= edge.depth);
+        }
+    }
+
+    #[test]
+    fn test_simulate_gustavson() {
+        let report = run(
+            |a, b| matmul_with(Dataflow::Gustavson, a, b),
+            &SimConfig::default(),
+        );
+        assert!(!report.deadlocked);
+        assert!(report
+            .nodes
+            .iter()
+            .any(|node| node.label.starts_with("Spacc")));
+    }
+
+    #[test]
+    fn test_parallel() {
+        let config = SimConfig::default();
+        let serial = run(|a, b| einsum("ik,jk->ij", vec![a, b]).unwrap(), &config);
+        let parallel = run(
+            |a, b| {
+                let schedule = Schedule::default().parallel('i', 4);
+                einsum_with("ik,jk->ij", vec![a, b], &schedule).unwrap()
+            },
+            &config,
+        );
+        assert!(!serial.deadlocked && !parallel.deadlocked);
+        assert!(parallel.cycles < serial.cycles);
+    }
+
+    #[test]
+    fn test_fifo_depth() {
+        let shallow = run(
+            matadd,
+            &SimConfig {
+                fifo_depth: 1,
+                ..Default::default()
+            },
+        );
+        let deep = run(
+            matadd,
+            &SimConfig {
+                fifo_depth: 8,
+                ..Default::default()
+            },
+        );
+        assert!(!shallow.deadlocked && !deep.deadlocked);
+        assert!(shallow.cycles >= deep.cycles);
+        let blocked = |report: &SimReport| report.nodes.iter().map(|n| n.blocked).sum::<usize>();
+        assert!(blocked(&shallow) > blocked(&deep));
+    }
+}
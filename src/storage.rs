@@ -0,0 +1,386 @@
+use std::{io, ops::Range};
+
+use crate::sam::LevelFormat;
+
+/// One level of a fibertree. Each position in a level is the reference its
+/// children are looked up by in the next level (or in the values array).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Level {
+    /// The fiber under parent position `p` holds every coordinate `i < size`
+    /// at position `p * size + i`.
+    Dense { size: usize },
+    /// The fiber under parent position `p` holds the coordinates
+    /// `crd[seg[p]..seg[p + 1]]`.
+    Compressed { seg: Vec<usize>, crd: Vec<usize> },
+    /// The fiber under parent position `p` holds the single coordinate `crd[p]`.
+    Singleton { crd: Vec<usize> },
+    /// The fiber under parent position `p` holds the coordinates set in its
+    /// `size`-bit mask, numbered from `seg[p]` in coordinate order.
+    Bitvector {
+        size: usize,
+        seg: Vec<usize>,
+        bits: Vec<u64>,
+    },
+}
+
+impl Level {
+    pub fn format(&self) -> LevelFormat {
+        match self {
+            Level::Dense { .. } => LevelFormat::Dense,
+            Level::Compressed { .. } => LevelFormat::Compressed,
+            Level::Singleton { .. } => LevelFormat::Singleton,
+            Level::Bitvector { .. } => LevelFormat::Bitvector,
+        }
+    }
+
+    /// The coordinates and positions of the fiber under `parent`.
+    pub fn fiber(&self, parent: usize) -> Vec<(usize, usize)> {
+        match self {
+            Level::Dense { size } => (0..*size).map(|i| (i, parent * size + i)).collect(),
+            Level::Compressed { seg, crd } => (seg[parent]..seg[parent + 1])
+                .map(|pos| (crd[pos], pos))
+                .collect(),
+            Level::Singleton { crd } => vec![(crd[parent], parent)],
+            Level::Bitvector { size, seg, bits } => (0..*size)
+                .filter(|i| {
+                    let bit = parent * size + i;
+                    bits[bit / 64] & (1 << (bit % 64)) != 0
+                })
+                .enumerate()
+                .map(|(rank, i)| (i, seg[parent] + rank))
+                .collect(),
+        }
+    }
+}
+
+/// In-memory data for a tensor read by `Fiberlookup` and `Arrayval`, stored in
+/// the fibertree layout SAM assumes: one `Level` per dimension followed by a
+/// values array indexed by positions in the last level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseTensor {
+    pub shape: Vec<usize>,
+    pub levels: Vec<Level>,
+    pub vals: Vec<f64>,
+}
+
+impl SparseTensor {
+    /// Builds a tensor with every level compressed from coordinate/value pairs
+    /// in any order, summing duplicates.
+    pub fn from_coo(
+        shape: Vec<usize>,
+        entries: impl IntoIterator<Item = (Vec<usize>, f64)>,
+    ) -> Self {
+        let formats = vec![LevelFormat::Compressed; shape.len()];
+        Self::from_coo_with_formats(shape, &formats, entries)
+    }
+
+    /// Builds a tensor stored as `formats` from coordinate/value pairs in any
+    /// order, summing duplicates.
+    ///
+    /// A level followed by a singleton level gets one position per entry, so
+    /// COO is `[Compressed, Singleton]`. Panics if the entries cannot be stored
+    /// as `formats`, see `try_from_coo_with_formats`.
+    pub fn from_coo_with_formats(
+        shape: Vec<usize>,
+        formats: &[LevelFormat],
+        entries: impl IntoIterator<Item = (Vec<usize>, f64)>,
+    ) -> Self {
+        Self::try_from_coo_with_formats(shape, formats, entries)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Builds a tensor like `from_coo_with_formats`, or returns why the
+    /// entries cannot be stored as `formats`: a singleton level needs exactly
+    /// one entry under each position of the level above, so under a dense or
+    /// bitvector level every coordinate of that level needs exactly one entry.
+    pub fn try_from_coo_with_formats(
+        shape: Vec<usize>,
+        formats: &[LevelFormat],
+        entries: impl IntoIterator<Item = (Vec<usize>, f64)>,
+    ) -> Result<Self, String> {
+        if shape.len() != formats.len() {
+            return Err(format!(
+                "Expected {} level formats, got {}",
+                shape.len(),
+                formats.len()
+            ));
+        }
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        for (coords, _) in &entries {
+            if coords.len() != shape.len() || coords.iter().zip(&shape).any(|(c, d)| c >= d) {
+                return Err(format!(
+                    "Coordinate {coords:?} is out of bounds for shape {shape:?}"
+                ));
+            }
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.dedup_by(|(coords, val), (prev_coords, prev_val)| {
+            let duplicate = coords == prev_coords;
+            if duplicate {
+                *prev_val += *val;
+            }
+            duplicate
+        });
+
+        // The range of entries under each position of the previous level.
+        let mut positions: Vec<Range<usize>> = std::iter::once(0..entries.len()).collect();
+        let mut levels = vec![];
+        for (level, &format) in formats.iter().enumerate() {
+            let size = shape[level];
+            let unique = formats.get(level + 1) != Some(&LevelFormat::Singleton);
+            // Splits a parent's entries into one range per child position.
+            let children = |range: Range<usize>| {
+                let mut groups: Vec<(usize, Range<usize>)> = vec![];
+                for idx in range {
+                    let crd = entries[idx].0[level];
+                    match groups.last_mut() {
+                        Some((last, group)) if unique && *last == crd => group.end = idx + 1,
+                        _ => groups.push((crd, idx..idx + 1)),
+                    }
+                }
+                groups
+            };
+            let shared = || {
+                format!("Level {level} has several entries per coordinate above a singleton level")
+            };
+            let mut next = vec![];
+            levels.push(match format {
+                LevelFormat::Dense => {
+                    for range in positions {
+                        let mut groups = children(range).into_iter().peekable();
+                        for i in 0..size {
+                            next.push(match groups.next_if(|(crd, _)| *crd == i) {
+                                Some((_, group)) => group,
+                                None => 0..0,
+                            });
+                        }
+                        if groups.next().is_some() {
+                            return Err(shared());
+                        }
+                    }
+                    Level::Dense { size }
+                }
+                LevelFormat::Compressed => {
+                    let (mut seg, mut crd) = (vec![0], vec![]);
+                    for range in positions {
+                        for (c, group) in children(range) {
+                            crd.push(c);
+                            next.push(group);
+                        }
+                        seg.push(crd.len());
+                    }
+                    Level::Compressed { seg, crd }
+                }
+                LevelFormat::Singleton => {
+                    let mut crd = vec![];
+                    for range in positions {
+                        let groups = children(range);
+                        let [(c, group)] = &groups[..] else {
+                            return Err(format!(
+                                "Singleton level {level} needs exactly one entry per parent, got {}",
+                                groups.len()
+                            ));
+                        };
+                        crd.push(*c);
+                        next.push(group.clone());
+                    }
+                    Level::Singleton { crd }
+                }
+                LevelFormat::Bitvector => {
+                    let mut seg = vec![0];
+                    let mut bits = vec![0u64; (positions.len() * size).div_ceil(64)];
+                    for (parent, range) in positions.into_iter().enumerate() {
+                        let groups = children(range);
+                        if groups.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                            return Err(shared());
+                        }
+                        for (c, group) in groups {
+                            let bit = parent * size + c;
+                            bits[bit / 64] |= 1 << (bit % 64);
+                            next.push(group);
+                        }
+                        seg.push(next.len());
+                    }
+                    Level::Bitvector { size, seg, bits }
+                }
+            });
+            positions = next;
+        }
+        let vals = positions
+            .into_iter()
+            .map(|range| entries[range].iter().map(|(_, val)| val).sum())
+            .collect();
+        Ok(Self {
+            shape,
+            levels,
+            vals,
+        })
+    }
+
+    pub fn order(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn formats(&self) -> Vec<LevelFormat> {
+        self.levels.iter().map(Level::format).collect()
+    }
+
+    /// The coordinates and child references of the fiber at `reference` in
+    /// `level`.
+    pub fn fiber(&self, level: usize, reference: usize) -> Vec<(usize, usize)> {
+        self.levels[level].fiber(reference)
+    }
+
+    pub fn value(&self, reference: usize) -> f64 {
+        self.vals[reference]
+    }
+
+    /// Lists the stored entries in storage order, which is lexicographic
+    /// coordinate order. Dense levels contribute their explicit zeros.
+    pub fn to_coo(&self) -> Vec<(Vec<usize>, f64)> {
+        let mut entries = vec![];
+        let mut stack = vec![(0, 0, vec![])];
+        while let Some((level, reference, coords)) = stack.pop() {
+            if level == self.order() {
+                entries.push((coords, self.value(reference)));
+                continue;
+            }
+            for (crd, child) in self.fiber(level, reference).into_iter().rev() {
+                let mut coords = coords.clone();
+                coords.push(crd);
+                stack.push((level + 1, child, coords));
+            }
+        }
+        entries
+    }
+}
+
+/// The error the tensor file readers and writers report: `InvalidData` for a
+/// malformed file, `InvalidInput` for formats or orderings it cannot be read as.
+pub(crate) fn invalid(kind: io::ErrorKind, message: impl Into<String>) -> io::Error {
+    io::Error::new(kind, message.into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sam::LevelFormat;
+
+    use super::{Level, SparseTensor};
+
+    /// [[1, 0, 2], [0, 0, 0], [0, 3, 0]]
+    fn entries() -> Vec<(Vec<usize>, f64)> {
+        vec![(vec![2, 1], 3.0), (vec![0, 2], 2.0), (vec![0, 0], 1.0)]
+    }
+
+    #[test]
+    fn test_from_coo() {
+        let tensor = SparseTensor::from_coo(vec![3, 3], entries());
+        assert_eq!(
+            tensor.levels,
+            vec![
+                Level::Compressed {
+                    seg: vec![0, 2],
+                    crd: vec![0, 2]
+                },
+                Level::Compressed {
+                    seg: vec![0, 2, 3],
+                    crd: vec![0, 2, 1]
+                }
+            ]
+        );
+        assert_eq!(tensor.vals, vec![1.0, 2.0, 3.0]);
+        assert_eq!(tensor.fiber(1, 1), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_duplicates_and_round_trip() {
+        let tensor = SparseTensor::from_coo(
+            vec![2, 2, 2],
+            vec![
+                (vec![1, 0, 1], 4.0),
+                (vec![0, 1, 0], 1.0),
+                (vec![1, 0, 1], 0.5),
+            ],
+        );
+        assert_eq!(
+            tensor.to_coo(),
+            vec![(vec![0, 1, 0], 1.0), (vec![1, 0, 1], 4.5)]
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let tensor = SparseTensor::from_coo(vec![4, 4], vec![]);
+        assert_eq!(
+            tensor.levels[0],
+            Level::Compressed {
+                seg: vec![0, 0],
+                crd: vec![]
+            }
+        );
+        assert!(tensor.to_coo().is_empty());
+    }
+
+    #[test]
+    fn test_csr() {
+        use LevelFormat::*;
+        let tensor =
+            SparseTensor::from_coo_with_formats(vec![3, 3], &[Dense, Compressed], entries());
+        assert_eq!(tensor.levels[0], Level::Dense { size: 3 });
+        assert_eq!(
+            tensor.levels[1],
+            Level::Compressed {
+                seg: vec![0, 2, 2, 3],
+                crd: vec![0, 2, 1]
+            }
+        );
+        assert_eq!(tensor.fiber(1, 1), vec![]);
+    }
+
+    #[test]
+    fn test_coo() {
+        use LevelFormat::*;
+        let tensor =
+            SparseTensor::from_coo_with_formats(vec![3, 3], &[Compressed, Singleton], entries());
+        assert_eq!(
+            tensor.levels,
+            vec![
+                Level::Compressed {
+                    seg: vec![0, 3],
+                    crd: vec![0, 0, 2]
+                },
+                Level::Singleton { crd: vec![0, 2, 1] }
+            ]
+        );
+        assert_eq!(
+            tensor.to_coo(),
+            SparseTensor::from_coo(vec![3, 3], entries()).to_coo()
+        );
+    }
+
+    #[test]
+    fn test_dense_and_bitvector() {
+        use LevelFormat::*;
+        let dense = SparseTensor::from_coo_with_formats(vec![3, 3], &[Dense, Dense], entries());
+        assert_eq!(
+            dense.vals,
+            vec![1.0, 0.0, 2.0, 0.0, 0.0, 0.0, 0.0, 3.0, 0.0]
+        );
+
+        let bitvector =
+            SparseTensor::from_coo_with_formats(vec![3, 3], &[Dense, Bitvector], entries());
+        assert_eq!(
+            bitvector.levels[1],
+            Level::Bitvector {
+                size: 3,
+                seg: vec![0, 2, 2, 3],
+                bits: vec![0b010_000_101]
+            }
+        );
+        assert_eq!(bitvector.fiber(1, 2), vec![(1, 2)]);
+        assert_eq!(
+            bitvector.to_coo(),
+            SparseTensor::from_coo(vec![3, 3], entries()).to_coo()
+        );
+    }
+}
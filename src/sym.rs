@@ -1,4 +1,10 @@
-use std::{cell::RefCell, fmt::Debug, hash::Hash, rc::Rc};
+use std::{
+    cell::RefCell,
+    fmt::{self, Debug},
+    hash::Hash,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use fxhash::{FxHashMap, FxHashSet};
 
@@ -22,10 +28,107 @@ impl std::fmt::Debug for Sym {
     }
 }
 
+/// What the tokens of a stream stand for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Ref,
+    Crd,
+    Val,
+    /// A stream of nothing but `Done`, signalling that a writer finished.
+    Signal,
+}
+
+/// The kind of a stream along with its nesting depth, the number of fiber
+/// levels its stop tokens close.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamType {
+    pub kind: StreamKind,
+    pub depth: usize,
+}
+
+/// Why an expression could not be staged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageError {
+    /// An input that is not an output of any op in the scope.
+    Unstaged(Sym),
+    /// An input of the wrong kind of stream.
+    Kind {
+        op: String,
+        port: usize,
+        expected: StreamKind,
+        found: StreamType,
+    },
+    /// Inputs of the right kinds that do not fit together.
+    Type { op: String, reason: String },
+    /// An op with a different number of outputs than its user expected.
+    Arity {
+        op: String,
+        expected: usize,
+        found: usize,
+    },
+    /// A tensor with `found` levels was staged as one with `expected`.
+    Rank { expected: usize, found: usize },
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StageError::Unstaged(sym) => write!(f, "{sym:?} is not staged in this scope"),
+            StageError::Kind {
+                op,
+                port,
+                expected,
+                found,
+            } => write!(
+                f,
+                "port {port} of {op} expects a {expected:?} stream, got a {:?} stream of depth {}",
+                found.kind, found.depth
+            ),
+            StageError::Type { op, reason } => write!(f, "cannot stage {op}: {reason}"),
+            StageError::Arity {
+                op,
+                expected,
+                found,
+            } => write!(f, "{op} has {found} outputs, expected {expected}"),
+            StageError::Rank { expected, found } => {
+                write!(f, "expected a tensor with {expected} levels, found {found}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// What `Expr::simplify` rewrites an expression to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Simplified<T> {
+    /// An expression to stage instead.
+    Expr(T),
+    /// Already staged syms that the outputs are the same streams as, one per
+    /// output.
+    Alias(Vec<Sym>),
+    /// An expression to stage instead, whose output `ports[i]` is the same
+    /// stream as output `i`.
+    Permuted(T, Vec<usize>),
+}
+
 pub trait Expr {
     fn arity(&self) -> usize;
     fn inputs(&self) -> Vec<Sym>;
 
+    /// This expression with `inputs`, in the order of `Expr::inputs`, in place
+    /// of its own.
+    fn with_inputs(&self, inputs: &[Sym]) -> Self
+    where
+        Self: Sized;
+
+    /// The kind of stream each input port expects, in the order of `inputs`.
+    fn input_kinds(&self) -> Vec<StreamKind>;
+
+    /// The types of the outputs given those of the inputs, which are of the
+    /// expected kinds, or why the inputs do not fit together.
+    fn output_types(&self, inputs: &[StreamType]) -> Result<Vec<StreamType>, String>;
+
     fn stage(self, scope: &ScopeRef<Self>) -> Vec<Sym>
     where
         Self: PartialEq + Eq + std::hash::Hash + Expr + Debug + Sized,
@@ -33,54 +136,217 @@ pub trait Expr {
         scope.borrow_mut().stage(self)
     }
 
-    fn simplify(self, _scope: &Scope<Self>) -> Self
+    fn try_stage(self, scope: &ScopeRef<Self>) -> Result<Vec<Sym>, StageError>
+    where
+        Self: PartialEq + Eq + std::hash::Hash + Expr + Debug + Sized,
+    {
+        scope.borrow_mut().try_stage(self)
+    }
+
+    /// Stages this expression like `try_stage`, expecting `N` outputs.
+    fn try_stage_n<const N: usize>(self, scope: &ScopeRef<Self>) -> Result<[Sym; N], StageError>
     where
         Self: PartialEq + Eq + std::hash::Hash + Expr + Debug + Sized,
     {
-        self
+        let op = format!("{self:?}");
+        let syms = self.try_stage(scope)?;
+        let found = syms.len();
+        syms.try_into().map_err(|_| StageError::Arity {
+            op,
+            expected: N,
+            found,
+        })
+    }
+
+    fn simplify(self, _scope: &Scope<Self>) -> Simplified<Self>
+    where
+        Self: PartialEq + Eq + std::hash::Hash + Expr + Debug + Sized,
+    {
+        Simplified::Expr(self)
+    }
+
+    /// The fill color of this expression's node in `Scope::to_dot`.
+    fn dot_color(&self) -> &'static str {
+        "turquoise"
     }
 }
 
 #[derive(Debug)]
 pub struct Scope<T> {
     cache: FxHashMap<T, Vec<Sym>>,
+    types: FxHashMap<Sym, StreamType>,
     counter: Counter,
+    id: usize,
+}
+
+fn next_scope_id() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
 }
 
 impl<T> Scope<T>
 where
     T: PartialEq + Eq + std::hash::Hash + Expr + Debug,
 {
+    /// Stages `expr` like `try_stage`, panicking if it cannot be staged.
     pub fn stage(&mut self, expr: T) -> Vec<Sym> {
-        let simplified = expr.simplify(&self);
-        match self.cache.get(&simplified) {
-            Some(existing) => existing.clone(),
-            None => {
-                let new_syms: Vec<_> = (0..simplified.arity())
-                    .map(|_| Sym {
-                        id: self.counter.next(),
-                    })
-                    .collect();
-                self.cache.insert(simplified, new_syms.clone());
-                new_syms
+        self.try_stage(expr).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Stages `expr` unless an identical op already is, returning its outputs.
+    /// Ops found in the scope are not type-checked again.
+    pub fn try_stage(&mut self, expr: T) -> Result<Vec<Sym>, StageError> {
+        if let Some(existing) = self.cache.get(&expr) {
+            return Ok(existing.clone());
+        }
+        let types = self.type_check(&expr)?;
+        let simplified = match expr.simplify(self) {
+            Simplified::Expr(simplified) => simplified,
+            Simplified::Alias(syms) => {
+                self.check_alias(&types, &syms, || format!("an op simplified to {syms:?}"))?;
+                return Ok(syms);
+            }
+            Simplified::Permuted(simplified, ports) => {
+                let staged = self.try_stage(simplified)?;
+                let syms: Vec<_> = ports.iter().map(|port| staged[*port]).collect();
+                self.check_alias(&types, &syms, || format!("an op permuted to {syms:?}"))?;
+                return Ok(syms);
+            }
+        };
+        if let Some(existing) = self.cache.get(&simplified) {
+            return Ok(existing.clone());
+        }
+        // Simplifying keeps the outputs of an op, so they have the same types.
+        let new_syms: Vec<_> = (0..simplified.arity())
+            .map(|_| Sym {
+                id: self.counter.next(),
+            })
+            .collect();
+        self.types.extend(new_syms.iter().copied().zip(types));
+        self.cache.insert(simplified, new_syms.clone());
+        Ok(new_syms)
+    }
+
+    /// Returns `syms` to stand for the outputs of `expr`, which is checked
+    /// like an op being staged.
+    pub fn alias(&self, expr: &T, syms: Vec<Sym>) -> Result<Vec<Sym>, StageError> {
+        let types = self.type_check(expr)?;
+        self.check_alias(&types, &syms, || format!("{expr:?}"))?;
+        Ok(syms)
+    }
+
+    /// Checks that `syms` are staged streams of the output `types` of the op
+    /// that `op` describes.
+    fn check_alias(
+        &self,
+        types: &[StreamType],
+        syms: &[Sym],
+        op: impl FnOnce() -> String,
+    ) -> Result<(), StageError> {
+        let aliased: Vec<_> = syms.iter().map(|sym| self.stream_type(*sym)).collect();
+        let expected: Vec<_> = types.iter().copied().map(Some).collect();
+        if aliased == expected {
+            return Ok(());
+        }
+        Err(StageError::Type {
+            op: op(),
+            reason: format!("its outputs {expected:?} are aliased to {aliased:?}"),
+        })
+    }
+
+    /// The types of the outputs of `expr`, checking that its inputs are
+    /// staged streams of the kinds it expects.
+    fn type_check(&self, expr: &T) -> Result<Vec<StreamType>, StageError> {
+        let inputs = expr
+            .inputs()
+            .into_iter()
+            .zip(expr.input_kinds())
+            .enumerate()
+            .map(|(port, (sym, expected))| {
+                let found = self.stream_type(sym).ok_or(StageError::Unstaged(sym))?;
+                if found.kind != expected {
+                    return Err(StageError::Kind {
+                        op: format!("{expr:?}"),
+                        port,
+                        expected,
+                        found,
+                    });
+                }
+                Ok(found)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let types = expr
+            .output_types(&inputs)
+            .map_err(|reason| StageError::Type {
+                op: format!("{expr:?}"),
+                reason,
+            })?;
+        assert_eq!(
+            types.len(),
+            expr.arity(),
+            "Expected a type per output of {expr:?}"
+        );
+        Ok(types)
+    }
+
+    /// Checks every op against the scope as staging it would, and that each
+    /// reads only streams staged before it.
+    pub fn verify(&self) -> Result<(), StageError> {
+        let mut staged = FxHashSet::default();
+        for (expr, syms) in self.program_order() {
+            if let Some(sym) = expr.inputs().into_iter().find(|sym| !staged.contains(sym)) {
+                return Err(StageError::Unstaged(sym));
+            }
+            let types = self.type_check(expr)?;
+            let recorded: Vec<_> = syms.iter().map(|sym| self.stream_type(*sym)).collect();
+            if recorded != types.iter().copied().map(Some).collect::<Vec<_>>() {
+                return Err(StageError::Type {
+                    op: format!("{expr:?}"),
+                    reason: format!("its outputs are recorded as {recorded:?}, not {types:?}"),
+                });
             }
+            staged.extend(syms.iter().copied());
         }
+        Ok(())
+    }
+
+    /// The kind and depth of the stream `sym`, if it is staged in this scope.
+    pub fn stream_type(&self, sym: Sym) -> Option<StreamType> {
+        self.types.get(&sym).copied()
+    }
+
+    /// Identifies this scope along with the ops it holds: the id changes
+    /// whenever ops are removed, so syms remembered under one id stay staged.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The op with the output `sym`, along with the port it is output on.
+    pub fn producer(&self, sym: Sym) -> Option<(&T, usize)> {
+        self.cache.iter().find_map(|(expr, syms)| {
+            let port = syms.iter().position(|output| *output == sym)?;
+            Some((expr, port))
+        })
     }
 
-    pub fn lookup<'a>(&'a self, sym: Sym) -> Option<&'a T> {
+    pub fn lookup(&self, sym: Sym) -> Option<&T> {
         let filtered = self.cache.iter().find(|(_, syms)| {syms.contains(&sym)});
         filtered.map(|x|x.0)
     }
 
-    fn program_order<'a>(
-        &'a self,
-    ) -> impl Iterator<Item = (&T, &Vec<Sym>)> + DoubleEndedIterator + 'a {
+    pub(crate) fn program_order(&self) -> impl DoubleEndedIterator<Item = (&T, &Vec<Sym>)> {
         let mut refs_and_deps: Vec<_> = self.cache.iter().collect();
         refs_and_deps.sort_unstable_by_key(|(_, deps)| deps.iter().map(|Sym { id }| *id).max());
         refs_and_deps.into_iter()
     }
 
     pub fn print(&self) {
+        print!("{}", self.dump());
+    }
+
+    /// The ops of this scope in program order, one per line.
+    pub fn dump(&self) -> String {
+        let mut ir = String::new();
         // sort cache by roots
         for (exprs, sym) in self.program_order() {
             let lhs = sym
@@ -88,11 +354,12 @@ where
                 .map(|s| format!("{:?}", *s))
                 .collect::<Vec<_>>()
                 .join(", ");
-            println!("{lhs} = {:?}", exprs);
+            ir += &format!("{lhs} = {:?}\n", exprs);
         }
+        ir
     }
 
-    fn calculate_live_syms(&self, mut roots: FxHashSet<Sym>) -> FxHashSet<Sym> {
+    pub(crate) fn calculate_live_syms(&self, mut roots: FxHashSet<Sym>) -> FxHashSet<Sym> {
         for (expr, outputs) in self.program_order().rev() {
             // If any of the outputs are in the roots set, then the op is live.
             if outputs.iter().any(|output| roots.contains(output)) {
@@ -105,6 +372,9 @@ where
     pub fn eliminate_dead_code(&mut self, roots: FxHashSet<Sym>) {
         let live = self.calculate_live_syms(roots);
         self.cache.retain(|_, v| v.iter().any(|x| live.contains(x)));
+        let staged: FxHashSet<Sym> = self.cache.values().flatten().copied().collect();
+        self.types.retain(|sym, _| staged.contains(sym));
+        self.id = next_scope_id();
     }
 
     pub fn to_dot(&self) -> graphviz_rust::dot_structures::Graph {
@@ -114,24 +384,25 @@ where
         for (i, (expr, syms)) in self.program_order().enumerate() {
             let ident = format!("op_{i}");
             let nodelabel = format!("{:?}", expr).replace("\"", "\\\"");
+            let color = expr.dot_color();
             stmts.push(
                 node!(
                     ident,
                     vec![
                         attr!("label", esc nodelabel),
-                        attr!("color", "turquoise"),
+                        attr!("color", esc color),
                         attr!("style", "filled")
                     ]
                 )
                 .into(),
             );
-            for sym in syms {
-                stmts.push(
-                    edge!(
-                        node_id!(ident) => node_id!(sym.id), vec![attr!("arrowhead", "none")]
-                    )
-                    .into(),
-                )
+            for (port, sym) in syms.iter().enumerate() {
+                let mut attrs = vec![attr!("arrowhead", "none")];
+                // Tell apart the outputs of ops like `Join` and `Spacc`.
+                if syms.len() > 1 {
+                    attrs.push(attr!("label", port));
+                }
+                stmts.push(edge!(node_id!(ident) => node_id!(sym.id), attrs).into())
             }
             for input in expr.inputs() {
                 stmts.push(
@@ -155,7 +426,9 @@ impl<T> Default for Scope<T> {
     fn default() -> Self {
         Self {
             cache: Default::default(),
+            types: Default::default(),
             counter: Default::default(),
+            id: next_scope_id(),
         }
     }
 }
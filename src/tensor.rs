@@ -1,45 +1,432 @@
-use std::rc::Rc;
+use std::{cell::RefCell, rc::Rc};
+
+use fxhash::FxHashMap;
 
 use crate::{
-    sam::SamOps,
-    sym::{Expr, ScopeRef, Sym},
+    elementwise::combine,
+    sam::{LevelFormat, PrimitiveOp, SamOps},
+    sym::{Expr, ScopeRef, StageError, Sym},
 };
 
+pub type MetaFn = Rc<dyn Fn(Sym, &ScopeRef<SamOps>) -> Result<(Sym, Sym), StageError>>;
+pub type CompFn = Rc<dyn Fn(Sym, &ScopeRef<SamOps>) -> Result<Sym, StageError>>;
+type StageFn<R> = Rc<dyn Fn(Sym, &ScopeRef<SamOps>) -> Result<R, StageError>>;
+
+#[derive(Clone)]
 pub struct Tensor {
-    pub meta: Vec<Rc<dyn Fn(Sym, &ScopeRef<SamOps>) -> (Sym, Sym)>>,
-    pub comp: Rc<dyn Fn(Sym, &ScopeRef<SamOps>) -> Sym>,
+    pub meta: Vec<MetaFn>,
+    pub comp: CompFn,
+}
+
+/// Implements the operator `$trait` on `$type` for every mix of owned and
+/// borrowed operands, combining them with `$combine` by value.
+macro_rules! binary_op {
+    ($type:ty, $trait:ident, $method:ident, $combine:expr) => {
+        impl std::ops::$trait for $type {
+            type Output = $type;
+            fn $method(self, rhs: $type) -> $type {
+                $combine(self, rhs)
+            }
+        }
+        impl std::ops::$trait<&$type> for $type {
+            type Output = $type;
+            fn $method(self, rhs: &$type) -> $type {
+                $combine(self, rhs.clone())
+            }
+        }
+        impl std::ops::$trait<$type> for &$type {
+            type Output = $type;
+            fn $method(self, rhs: $type) -> $type {
+                $combine(self.clone(), rhs)
+            }
+        }
+        impl std::ops::$trait for &$type {
+            type Output = $type;
+            fn $method(self, rhs: &$type) -> $type {
+                $combine(self.clone(), rhs.clone())
+            }
+        }
+    };
+}
+pub(crate) use binary_op;
+
+// Entry by entry, as `elementwise`.
+binary_op!(Tensor, Add, add, |a, b| combine(PrimitiveOp::Add, a, b));
+binary_op!(Tensor, Mul, mul, |a, b| combine(PrimitiveOp::Mul, a, b));
+
+/// The closure staging `level` of a tensor with the levels `meta`.
+pub fn level(meta: &[MetaFn], level: usize) -> Result<&MetaFn, StageError> {
+    meta.get(level).ok_or(StageError::Rank {
+        expected: level + 1,
+        found: meta.len(),
+    })
+}
+
+/// Wraps `stage` so it stages its ops once per reference stream and scope,
+/// returning the same syms, or error, when called again. Only the results
+/// for the scope last staged into are kept.
+fn memoize<R: Clone + 'static>(stage: StageFn<R>) -> StageFn<R> {
+    type Staged<R> = Result<R, StageError>;
+    let staged: RefCell<(usize, FxHashMap<Sym, Staged<R>>)> = Default::default();
+    Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
+        let id = scope.borrow().id();
+        {
+            let mut staged = staged.borrow_mut();
+            if staged.0 != id {
+                *staged = (id, FxHashMap::default());
+            }
+            if let Some(result) = staged.1.get(&refstream) {
+                return result.clone();
+            }
+        }
+        let result = stage(refstream, scope);
+        let mut staged = staged.borrow_mut();
+        if staged.0 == id {
+            staged.1.insert(refstream, result.clone());
+        }
+        result
+    })
+}
+
+impl Tensor {
+    /// A tensor with `levels` levels whose closures all fail with `err`, for
+    /// combinators that cannot build their result to report why once staged.
+    pub fn failed(err: StageError, levels: usize) -> Tensor {
+        let meta_err = err.clone();
+        let meta: MetaFn = Rc::new(move |_, _| Err(meta_err.clone()));
+        Tensor {
+            meta: vec![meta; levels],
+            comp: Rc::new(move |_, _| Err(err.clone())),
+        }
+    }
+
+    /// Multiplies this matrix by `rhs` like `matmul`.
+    pub fn matmul(&self, rhs: &Tensor) -> Tensor {
+        crate::matmul::matmul(self.clone(), rhs.clone())
+    }
+
+    /// Memoizes every closure of this tensor, so each of its levels and its
+    /// values are staged once per reference stream and scope. Hash-consing
+    /// would merge the ops of repeated calls anyway, so the staged graph is
+    /// the same; combinators call each closure of an operand from every one
+    /// of their own, though, so staging them again takes time exponential in
+    /// the depth of an expression.
+    pub fn memoized(self) -> Tensor {
+        Tensor {
+            meta: self.meta.into_iter().map(memoize).collect(),
+            comp: memoize(self.comp),
+        }
+    }
+
+    /// Wraps the closures of a tensor computed from the root reference stream,
+    /// like the ones `matmul` and `lower` build, so it can be an operand of
+    /// another combinator.
+    ///
+    /// Combinators call each level of an operand with references into the
+    /// level above, from every closure of their own. Called with the root
+    /// reference stream or its own references of the level above, the tensor
+    /// hands out its streams as computed from the root; called with any other
+    /// references, it looks up the matching fibers and values of those streams
+    /// like a stored tensor would. Every node of an expression is then staged
+    /// once, rather than once per reference stream of the combinators above
+    /// it, and computing from the root is memoized.
+    pub fn computed(self) -> Tensor {
+        self.memoized().buffered()
+    }
+
+    /// Wraps the closures of this tensor like `computed`, without memoizing
+    /// them.
+    pub(crate) fn buffered(self) -> Tensor {
+        let meta: Rc<Vec<MetaFn>> = Rc::new(self.meta);
+        let comp = self.comp;
+        // The streams of a level and the references into the level above.
+        let own = |meta: &[MetaFn], level: usize, scope: &ScopeRef<SamOps>| {
+            let [root] = SamOps::Root.try_stage_n(scope)?;
+            let outer = match level.checked_sub(1) {
+                Some(above) => Some(meta[above](root, scope)?),
+                None => None,
+            };
+            let parent = outer.map_or(root, |(reference, _)| reference);
+            Ok((outer, root, parent))
+        };
+        let lookups = (0..meta.len())
+            .map(|level| {
+                let meta = meta.clone();
+                Rc::new(move |reference, scope: &ScopeRef<SamOps>| {
+                    let (outer, root, parent) = own(&meta, level, scope)?;
+                    let (own_ref, crds) = meta[level](root, scope)?;
+                    if reference == root || reference == parent {
+                        return Ok((own_ref, crds));
+                    }
+                    let [found_ref, found_crds] = SamOps::Streamlookup {
+                        reference,
+                        outer: outer.map(|(_, crds)| crds),
+                        crds,
+                    }
+                    .try_stage_n(scope)?;
+                    Ok((found_ref, found_crds))
+                }) as MetaFn
+            })
+            .collect();
+        let levels = meta.len();
+        Tensor {
+            meta: lookups,
+            comp: Rc::new(move |reference, scope: &ScopeRef<SamOps>| {
+                let (_, root, parent) = own(&meta, levels, scope)?;
+                let values = comp(root, scope)?;
+                if reference == root || reference == parent {
+                    return Ok(values);
+                }
+                let [found] = SamOps::Vallookup { reference, values }.try_stage_n(scope)?;
+                Ok(found)
+            }),
+        }
+    }
+
+    /// Stages writers that store every level and the values of this tensor as
+    /// the output `name` with the given level formats, and returns their syms.
+    ///
+    /// Every `meta` closure and `comp` are called with the same `refstream`,
+    /// as for the tensors built by `matmul` and `matadd`.
+    pub fn stage_output(
+        &self,
+        name: &str,
+        formats: &[LevelFormat],
+        refstream: Sym,
+        scope: &ScopeRef<SamOps>,
+    ) -> Vec<Sym> {
+        self.try_stage_output(name, formats, refstream, scope)
+            .unwrap_or_else(|err| panic!("Cannot stage {name}: {err}"))
+    }
+
+    /// Stages writers like `stage_output`, or returns why this tensor cannot
+    /// be staged.
+    pub fn try_stage_output(
+        &self,
+        name: &str,
+        formats: &[LevelFormat],
+        refstream: Sym,
+        scope: &ScopeRef<SamOps>,
+    ) -> Result<Vec<Sym>, StageError> {
+        if formats.len() != self.meta.len() {
+            return Err(StageError::Rank {
+                expected: formats.len(),
+                found: self.meta.len(),
+            });
+        }
+        let mut writers = vec![];
+        for (level, (meta, &format)) in self.meta.iter().zip(formats).enumerate() {
+            let (_, coords) = meta(refstream, scope)?;
+            writers.push(
+                SamOps::Fiberwrite {
+                    coords,
+                    tensor: name.to_string(),
+                    level,
+                    format,
+                }
+                .try_stage(scope)?[0],
+            );
+        }
+        let values = (self.comp)(refstream, scope)?;
+        writers.push(
+            SamOps::Valwrite {
+                values,
+                tensor: name.to_string(),
+            }
+            .try_stage(scope)?[0],
+        );
+        Ok(writers)
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct InputTensor {
     pub name: String,
-    pub dims: usize,
+    /// The storage format of each level, outermost first.
+    pub formats: Vec<LevelFormat>,
 }
 
 impl InputTensor {
+    pub fn dims(&self) -> usize {
+        self.formats.len()
+    }
+
     pub fn stage(&self) -> Tensor {
-        let mut meta: Vec<Rc<dyn Fn(Sym, &ScopeRef<SamOps>) -> (Sym, Sym)>> = vec![];
-        for level in 0..self.dims {
+        let mut meta: Vec<MetaFn> = vec![];
+        for (level, &format) in self.formats.iter().enumerate() {
             let tensor = self.name.clone();
             meta.push(Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
-                let tmp = SamOps::Fiberlookup {
+                let [reference, crds] = SamOps::Fiberlookup {
                     reference: refstream,
                     tensor: tensor.clone(),
                     level,
+                    format,
                 }
-                .stage(scope);
-                (tmp[0], tmp[1])
+                .try_stage_n(scope)?;
+                Ok((reference, crds))
             }));
         }
         let tensor = self.name.clone();
         Tensor {
             meta,
             comp: Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
-                SamOps::Arrayval {
+                Ok(SamOps::Arrayval {
                     reference: refstream,
                     tensor: tensor.clone(),
                 }
-                .stage(scope)[0]
+                .try_stage(scope)?[0])
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::{
+        elementwise::elementwise,
+        matadd::matadd,
+        matmul::matmul,
+        sam::{LevelFormat, PrimitiveOp, SamOps},
+        sym::{Expr, ScopeRef, StageError},
+    };
+
+    use super::{InputTensor, MetaFn, Tensor};
+
+    #[test]
+    fn test_stage_formats() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let csr = InputTensor {
+            name: "A".to_string(),
+            formats: vec![LevelFormat::Dense, LevelFormat::Compressed],
+        };
+        let tensor = csr.stage();
+        let (r0, _) = (tensor.meta[0])(root, &scope).unwrap();
+        let (r1, _) = (tensor.meta[1])(r0, &scope).unwrap();
+
+        let sc = scope.borrow();
+        let format_of = |sym| match sc.lookup(sym) {
+            Some(SamOps::Fiberlookup { format, .. }) => *format,
+            other => panic!("Expected a Fiberlookup, got {other:?}"),
+        };
+        assert_eq!(format_of(r0), LevelFormat::Dense);
+        assert_eq!(format_of(r1), LevelFormat::Compressed);
+    }
+
+    #[test]
+    fn test_operators() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let [a, b, c] = ["A", "B", "C"].map(|name| {
+            InputTensor {
+                name: name.to_string(),
+                formats: vec![LevelFormat::Compressed; 2],
+            }
+            .stage()
+        });
+        let same = |lhs: Tensor, rhs: Tensor| {
+            (lhs.comp)(root, &scope).unwrap() == (rhs.comp)(root, &scope).unwrap()
+        };
+
+        let product = elementwise(PrimitiveOp::Mul, a.clone(), b.clone()).unwrap();
+        assert!(same(&a * &b + &c, matadd(product, c.clone())));
+        assert!(same(
+            a.matmul(&b) + &c,
+            matadd(matmul(a.clone(), b.clone()), c)
+        ));
+    }
+
+    #[test]
+    fn test_rank_error() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let [a, x] = [2, 1].map(|levels| {
+            InputTensor {
+                name: format!("T{levels}"),
+                formats: vec![LevelFormat::Compressed; levels],
+            }
+            .stage()
+        });
+        let result =
+            a.matmul(&x)
+                .try_stage_output("X", &[LevelFormat::Compressed; 2], root, &scope);
+        assert_eq!(
+            result,
+            Err(StageError::Rank {
+                expected: 2,
+                found: 1
+            })
+        );
+
+        // Operands of different ranks, and one format too many.
+        let sum = &a + &x;
+        let result = sum.try_stage_output("X", &[LevelFormat::Compressed; 2], root, &scope);
+        assert_eq!(
+            result,
+            Err(StageError::Rank {
+                expected: 2,
+                found: 1
+            })
+        );
+        let result = a.try_stage_output("X", &[LevelFormat::Compressed; 3], root, &scope);
+        assert_eq!(
+            result,
+            Err(StageError::Rank {
+                expected: 3,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_memoized_staging() {
+        let lookups = Rc::new(Cell::new(0));
+        // A matrix whose level lookups are counted.
+        let leaf = |depth: usize| {
+            let tensor = InputTensor {
+                name: format!("A{depth}"),
+                formats: vec![LevelFormat::Compressed; 2],
+            }
+            .stage();
+            let counted = |meta: MetaFn| {
+                let lookups = lookups.clone();
+                Rc::new(move |refstream, scope: &ScopeRef<SamOps>| {
+                    lookups.set(lookups.get() + 1);
+                    meta(refstream, scope)
+                }) as MetaFn
+            };
+            Tensor {
+                meta: tensor.meta.into_iter().map(counted).collect(),
+                comp: tensor.comp,
+            }
+        };
+        let mut chain = leaf(0);
+        for depth in 1..20 {
+            chain = match depth % 2 {
+                0 => matmul(chain, leaf(depth)),
+                _ => matadd(chain, leaf(depth)),
+            };
+        }
+
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let writers = chain.stage_output("X", &[LevelFormat::Compressed; 2], root, &scope);
+        // Each combinator looks up a handful of fibers of every operand level.
+        assert!(lookups.get() <= 20 * 2 * 3, "{} lookups", lookups.get());
+        let ops = scope.borrow().program_order().count();
+        assert!(ops <= 20 * 16, "{ops} ops");
+
+        // Only the last scope is remembered: staging into another one and
+        // back looks the levels up again, finding the ops already staged.
+        let other = ScopeRef::<SamOps>::default();
+        let other_root = SamOps::Root.stage(&other)[0];
+        chain.stage_output("X", &[LevelFormat::Compressed; 2], other_root, &other);
+        let staged = lookups.get();
+        let again = chain.stage_output("X", &[LevelFormat::Compressed; 2], root, &scope);
+        assert!(lookups.get() > staged);
+        assert_eq!(again, writers);
+        assert_eq!(scope.borrow().program_order().count(), ops);
+    }
+}
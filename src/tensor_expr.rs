@@ -0,0 +1,372 @@
+use std::fmt;
+
+use fxhash::FxHashMap;
+
+use crate::{
+    contract::contract,
+    elementwise::elementwise,
+    lower::LowerError,
+    matmul::{matmul_with, Dataflow},
+    sam::PrimitiveOp,
+    tensor::{binary_op, InputTensor, Tensor},
+};
+
+/// A tensor expression kept as data, so it can be printed, compared, hashed
+/// and rewritten before it is lowered to the closures of a `Tensor`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TensorExpr {
+    Input(InputTensor),
+    /// Combines two tensors of the same rank entry by entry, as `elementwise`.
+    Elementwise {
+        op: PrimitiveOp,
+        lhs: Box<TensorExpr>,
+        rhs: Box<TensorExpr>,
+    },
+    Matmul {
+        dataflow: Dataflow,
+        lhs: Box<TensorExpr>,
+        rhs: Box<TensorExpr>,
+    },
+    /// Multiplies two tensors with labelled levels and sums over the labels
+    /// missing from `output`, as `contract`.
+    Contract {
+        lhs: Box<TensorExpr>,
+        lhs_indices: Vec<char>,
+        rhs: Box<TensorExpr>,
+        rhs_indices: Vec<char>,
+        output: Vec<char>,
+    },
+}
+
+impl From<InputTensor> for TensorExpr {
+    fn from(input: InputTensor) -> Self {
+        TensorExpr::Input(input)
+    }
+}
+
+fn binary(op: PrimitiveOp, lhs: TensorExpr, rhs: TensorExpr) -> TensorExpr {
+    TensorExpr::Elementwise {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+binary_op!(TensorExpr, Add, add, |a, b| binary(PrimitiveOp::Add, a, b));
+binary_op!(TensorExpr, Mul, mul, |a, b| binary(PrimitiveOp::Mul, a, b));
+
+impl TensorExpr {
+    /// The product of this matrix and `rhs` with the inner-product dataflow.
+    pub fn matmul(&self, rhs: &TensorExpr) -> TensorExpr {
+        TensorExpr::Matmul {
+            dataflow: Dataflow::InnerProduct,
+            lhs: Box::new(self.clone()),
+            rhs: Box::new(rhs.clone()),
+        }
+    }
+
+    /// The number of levels of the tensor this expression computes.
+    pub fn rank(&self) -> usize {
+        match self {
+            TensorExpr::Input(input) => input.dims(),
+            TensorExpr::Elementwise { lhs, .. } => lhs.rank(),
+            TensorExpr::Matmul { .. } => 2,
+            TensorExpr::Contract { output, .. } => output.len(),
+        }
+    }
+
+    /// The operands of the outermost operation, left to right.
+    pub fn children(&self) -> Vec<&TensorExpr> {
+        match self {
+            TensorExpr::Input(_) => vec![],
+            TensorExpr::Elementwise { lhs, rhs, .. }
+            | TensorExpr::Matmul { lhs, rhs, .. }
+            | TensorExpr::Contract { lhs, rhs, .. } => vec![lhs, rhs],
+        }
+    }
+
+    /// The input tensors this expression reads, in order of first use.
+    pub fn inputs(&self) -> Vec<&InputTensor> {
+        let mut inputs = vec![];
+        self.collect_inputs(&mut inputs);
+        inputs
+    }
+
+    fn collect_inputs<'a>(&'a self, inputs: &mut Vec<&'a InputTensor>) {
+        match self {
+            TensorExpr::Input(input) => {
+                if !inputs.contains(&input) {
+                    inputs.push(input);
+                }
+            }
+            _ => self
+                .children()
+                .into_iter()
+                .for_each(|child| child.collect_inputs(inputs)),
+        }
+    }
+
+    /// Rewrites this expression bottom-up: the operands of every node are
+    /// rewritten first, then `rule` may replace the node itself.
+    pub fn rewrite(&self, rule: &impl Fn(&TensorExpr) -> Option<TensorExpr>) -> TensorExpr {
+        let rewritten = |expr: &TensorExpr| Box::new(expr.rewrite(rule));
+        let expr = match self {
+            TensorExpr::Input(_) => self.clone(),
+            TensorExpr::Elementwise { op, lhs, rhs } => TensorExpr::Elementwise {
+                op: *op,
+                lhs: rewritten(lhs),
+                rhs: rewritten(rhs),
+            },
+            TensorExpr::Matmul { dataflow, lhs, rhs } => TensorExpr::Matmul {
+                dataflow: *dataflow,
+                lhs: rewritten(lhs),
+                rhs: rewritten(rhs),
+            },
+            TensorExpr::Contract {
+                lhs,
+                lhs_indices,
+                rhs,
+                rhs_indices,
+                output,
+            } => TensorExpr::Contract {
+                lhs: rewritten(lhs),
+                lhs_indices: lhs_indices.clone(),
+                rhs: rewritten(rhs),
+                rhs_indices: rhs_indices.clone(),
+                output: output.clone(),
+            },
+        };
+        rule(&expr).unwrap_or(expr)
+    }
+
+    /// Lowers this expression to the closures staging its `SamOps`. A
+    /// subexpression that occurs several times is lowered once and shared.
+    pub fn lower(&self) -> Result<Tensor, LowerError> {
+        self.lower_shared(&mut FxHashMap::default())
+    }
+
+    fn lower_shared<'a>(
+        &'a self,
+        lowered: &mut FxHashMap<&'a TensorExpr, Tensor>,
+    ) -> Result<Tensor, LowerError> {
+        if let Some(tensor) = lowered.get(self) {
+            return Ok(tensor.clone());
+        }
+        let tensor = match self {
+            TensorExpr::Input(input) => input.stage(),
+            TensorExpr::Elementwise { op, lhs, rhs } => {
+                if lhs.rank() != rhs.rank() {
+                    return Err(LowerError::Rank {
+                        operand: rhs.to_string(),
+                        expected: lhs.rank(),
+                        found: rhs.rank(),
+                    });
+                }
+                elementwise(*op, lhs.lower_shared(lowered)?, rhs.lower_shared(lowered)?)?
+            }
+            TensorExpr::Matmul { dataflow, lhs, rhs } => {
+                if let Some(operand) = [lhs, rhs].into_iter().find(|operand| operand.rank() != 2) {
+                    return Err(LowerError::Rank {
+                        operand: operand.to_string(),
+                        expected: 2,
+                        found: operand.rank(),
+                    });
+                }
+                matmul_with(
+                    *dataflow,
+                    lhs.lower_shared(lowered)?,
+                    rhs.lower_shared(lowered)?,
+                )
+            }
+            TensorExpr::Contract {
+                lhs,
+                lhs_indices,
+                rhs,
+                rhs_indices,
+                output,
+            } => {
+                for (operand, indices) in [(lhs, lhs_indices), (rhs, rhs_indices)] {
+                    if operand.rank() != indices.len() {
+                        return Err(LowerError::Rank {
+                            operand: operand.to_string(),
+                            expected: operand.rank(),
+                            found: indices.len(),
+                        });
+                    }
+                }
+                contract(
+                    lhs.lower_shared(lowered)?,
+                    lhs_indices,
+                    rhs.lower_shared(lowered)?,
+                    rhs_indices,
+                    output,
+                )?
+            }
+        };
+        lowered.insert(self, tensor.clone());
+        Ok(tensor)
+    }
+}
+
+impl fmt::Display for TensorExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let indices = |indices: &[char]| indices.iter().collect::<String>();
+        match self {
+            TensorExpr::Input(input) => write!(f, "{}", input.name),
+            TensorExpr::Elementwise { op, lhs, rhs } => {
+                let op = match op {
+                    PrimitiveOp::Add => '+',
+                    PrimitiveOp::Mul => '*',
+                };
+                write!(f, "({lhs} {op} {rhs})")
+            }
+            TensorExpr::Matmul {
+                dataflow: Dataflow::InnerProduct,
+                lhs,
+                rhs,
+            } => write!(f, "({lhs} @ {rhs})"),
+            TensorExpr::Matmul { dataflow, lhs, rhs } => {
+                write!(f, "matmul[{dataflow:?}]({lhs}, {rhs})")
+            }
+            TensorExpr::Contract {
+                lhs,
+                lhs_indices,
+                rhs,
+                rhs_indices,
+                output,
+            } => write!(
+                f,
+                "contract({lhs}[{}], {rhs}[{}] -> [{}])",
+                indices(lhs_indices),
+                indices(rhs_indices),
+                indices(output)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxhash::FxHashSet;
+
+    use crate::{
+        lower::LowerError,
+        matadd::matadd,
+        matmul::matmul,
+        sam::{LevelFormat::Compressed, PrimitiveOp, SamOps},
+        sym::{Expr, ScopeRef},
+        tensor::InputTensor,
+        test_util::{input, run},
+    };
+
+    use super::TensorExpr;
+
+    /// `(A @ B) + (A @ C)`.
+    fn sum_of_products() -> TensorExpr {
+        let [a, b, c] = ["A", "B", "C"].map(|name| TensorExpr::from(input(name)));
+        a.matmul(&b) + a.matmul(&c)
+    }
+
+    #[test]
+    fn test_inspect() {
+        let expr = sum_of_products();
+        assert_eq!(expr.to_string(), "((A @ B) + (A @ C))");
+        assert_eq!(expr.rank(), 2);
+        let names: Vec<_> = expr
+            .inputs()
+            .iter()
+            .map(|input| input.name.as_str())
+            .collect();
+        assert_eq!(names, ["A", "B", "C"]);
+        let distinct: FxHashSet<_> = [expr.clone(), sum_of_products()].into_iter().collect();
+        assert_eq!(distinct.len(), 1);
+        let products = expr.children();
+        assert_ne!(products[0], products[1]);
+    }
+
+    #[test]
+    fn test_operators() {
+        let [a, b, c] = ["A", "B", "C"].map(|name| TensorExpr::from(input(name)));
+        let expr = &a * &b + &c;
+        assert_eq!(expr.to_string(), "((A * B) + C)");
+        assert_eq!(
+            expr,
+            TensorExpr::Elementwise {
+                op: PrimitiveOp::Add,
+                lhs: Box::new(TensorExpr::Elementwise {
+                    op: PrimitiveOp::Mul,
+                    lhs: Box::new(a),
+                    rhs: Box::new(b),
+                }),
+                rhs: Box::new(c),
+            }
+        );
+    }
+
+    #[test]
+    fn test_lower_like_combinators() {
+        let scope = ScopeRef::<SamOps>::default();
+        let root = SamOps::Root.stage(&scope)[0];
+        let [a, b, c] = ["A", "B", "C"].map(|name| input(name).stage());
+        let staged = matadd(matmul(a.clone(), b), matmul(a, c));
+        let lowered = sum_of_products().lower().unwrap();
+        assert_eq!(
+            (staged.comp)(root, &scope).unwrap(),
+            (lowered.comp)(root, &scope).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rewrite() {
+        // Factor A @ B + A @ C into A @ (B + C).
+        let factor = |expr: &TensorExpr| {
+            let TensorExpr::Elementwise {
+                op: PrimitiveOp::Add,
+                lhs,
+                rhs,
+            } = expr
+            else {
+                return None;
+            };
+            match (lhs.as_ref(), rhs.as_ref()) {
+                (
+                    TensorExpr::Matmul {
+                        dataflow,
+                        lhs: a,
+                        rhs: b,
+                    },
+                    TensorExpr::Matmul {
+                        dataflow: other,
+                        lhs: a2,
+                        rhs: c,
+                    },
+                ) if a == a2 && dataflow == other => Some(TensorExpr::Matmul {
+                    dataflow: *dataflow,
+                    lhs: a.clone(),
+                    rhs: Box::new(b.as_ref() + c.as_ref()),
+                }),
+                _ => None,
+            }
+        };
+        let expr = sum_of_products();
+        let factored = expr.rewrite(&factor);
+        assert_eq!(factored.to_string(), "(A @ (B + C))");
+        assert_eq!(run(factored.lower().unwrap()), run(expr.lower().unwrap()));
+    }
+
+    #[test]
+    fn test_rank_errors() {
+        let vector = TensorExpr::from(InputTensor {
+            name: "x".to_string(),
+            formats: vec![Compressed],
+        });
+        let a = TensorExpr::from(input("A"));
+        let rank_error = Some(LowerError::Rank {
+            operand: "x".to_string(),
+            expected: 2,
+            found: 1,
+        });
+        assert_eq!((&a + &vector).lower().err(), rank_error);
+        assert_eq!(a.matmul(&vector).lower().err(), rank_error);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs::File,
+    io::{
+        self, BufRead, BufReader,
+        ErrorKind::{InvalidData, InvalidInput},
+    },
+    path::Path,
+};
+
+use crate::{
+    sam::LevelFormat,
+    storage::{invalid, SparseTensor},
+};
+
+/// Loads a FROSTT `.tns` file into a tensor whose level `l` stores mode
+/// `mode_order[l]` in format `formats[l]`.
+pub fn load_tns(
+    path: impl AsRef<Path>,
+    mode_order: &[usize],
+    formats: &[LevelFormat],
+) -> io::Result<SparseTensor> {
+    read_tns(BufReader::new(File::open(path)?), mode_order, formats)
+}
+
+/// Parses a FROSTT coordinate list: one `i_1 ... i_n value` line per nonzero
+/// with 1-based indices and `#` comments. Every line must have one index per
+/// mode in `mode_order`, and each dimension is the largest index seen in it.
+pub fn read_tns(
+    reader: impl BufRead,
+    mode_order: &[usize],
+    formats: &[LevelFormat],
+) -> io::Result<SparseTensor> {
+    let order = mode_order.len();
+    let mut sorted_modes = mode_order.to_vec();
+    sorted_modes.sort_unstable();
+    if sorted_modes != (0..order).collect::<Vec<_>>() {
+        return Err(invalid(
+            InvalidInput,
+            format!("Mode order {mode_order:?} is not a permutation"),
+        ));
+    }
+    if formats.len() != order {
+        return Err(invalid(
+            InvalidInput,
+            format!("Expected {order} level formats, got {}", formats.len()),
+        ));
+    }
+
+    let mut shape = vec![0; order];
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<_> = line.split_whitespace().collect();
+        let Some((value, indices)) = words.split_last() else {
+            continue;
+        };
+        if indices.len() != order {
+            return Err(invalid(
+                InvalidData,
+                format!("Expected {order} indices per entry, got: {line}"),
+            ));
+        }
+        let value: f64 = value
+            .parse()
+            .map_err(|_| invalid(InvalidData, format!("Bad value in: {line}")))?;
+        let mut coords = vec![0; order];
+        for (level, &mode) in mode_order.iter().enumerate() {
+            let index = match indices[mode].parse::<usize>() {
+                Ok(index) if index > 0 => index - 1,
+                _ => return Err(invalid(InvalidData, format!("Bad index in: {line}"))),
+            };
+            shape[level] = shape[level].max(index + 1);
+            coords[level] = index;
+        }
+        entries.push((coords, value));
+    }
+    SparseTensor::try_from_coo_with_formats(shape, formats, entries)
+        .map_err(|message| invalid(InvalidInput, message))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind};
+
+    use crate::sam::LevelFormat::{Bitvector, Compressed, Dense, Singleton};
+
+    use super::read_tns;
+
+    const FILE: &str = "# a 3-mode tensor
+1 1 2 1.0
+2 3 1 2.5
+
+1 2 1 -1
+";
+
+    #[test]
+    fn test_read_tns() {
+        let tensor = read_tns(Cursor::new(FILE), &[0, 1, 2], &[Compressed; 3]).unwrap();
+        assert_eq!(tensor.shape, vec![2, 3, 2]);
+        assert_eq!(
+            tensor.to_coo(),
+            vec![
+                (vec![0, 0, 1], 1.0),
+                (vec![0, 1, 0], -1.0),
+                (vec![1, 2, 0], 2.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mode_order() {
+        let tensor = read_tns(Cursor::new(FILE), &[2, 0, 1], &[Compressed; 3]).unwrap();
+        assert_eq!(tensor.shape, vec![2, 2, 3]);
+        assert_eq!(
+            tensor.to_coo(),
+            vec![
+                (vec![0, 0, 1], -1.0),
+                (vec![0, 1, 2], 2.5),
+                (vec![1, 0, 0], 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let kind = |mode_order: &[usize], formats: &[_]| {
+            read_tns(Cursor::new(FILE), mode_order, formats)
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(kind(&[0, 0, 1], &[Compressed; 3]), ErrorKind::InvalidInput);
+        assert_eq!(kind(&[0, 1, 2], &[Compressed; 2]), ErrorKind::InvalidInput);
+        assert_eq!(kind(&[0, 1], &[Compressed; 2]), ErrorKind::InvalidData);
+        assert!(read_tns(Cursor::new("0 1 1.0\n"), &[0, 1], &[Compressed; 2]).is_err());
+        // Singleton levels need exactly one entry under each position above.
+        assert_eq!(
+            kind(&[0, 1, 2], &[Singleton, Compressed, Compressed]),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            kind(&[0, 1, 2], &[Dense, Singleton, Compressed]),
+            ErrorKind::InvalidInput
+        );
+        let shared = read_tns(
+            Cursor::new("1 1 1 1.0\n1 1 2 2.0\n"),
+            &[0, 1, 2],
+            &[Compressed, Bitvector, Singleton],
+        );
+        assert_eq!(shared.unwrap_err().kind(), ErrorKind::InvalidInput);
+        let coo = [Compressed, Singleton, Singleton];
+        assert!(read_tns(Cursor::new(FILE), &[0, 1, 2], &coo).is_ok());
+    }
+}